@@ -27,5 +27,5 @@ while read LINE; do
       mv -T "$(brp 2)" "$(brp)"
       ;;
   esac
-  cargo bench > "$(brp 2)" && cargo benchcmp --threshold 4 "$(brp)" "$(brp 2)"
+  cargo bench --features nightly > "$(brp 2)" && cargo benchcmp --threshold 4 "$(brp)" "$(brp 2)"
 done
@@ -0,0 +1,125 @@
+#![feature(test)]
+
+use crulz::{
+    ast::{CmdEvalArgs, Lift as _, Mangle as _, Node},
+    interp::{eval, EvalContext},
+    parser::{parse_toplevel, Options as ParserOptions},
+};
+use std::collections::HashMap;
+extern crate test;
+
+fn bench_input(b: &mut test::Bencher, input: &str) {
+    let opts = ParserOptions::default();
+    let ast = parse_toplevel(input.as_bytes(), &opts).unwrap();
+    b.iter(|| {
+        let mut data = ast.clone();
+        let mut ctx = EvalContext::new(opts.clone(), HashMap::new());
+        eval(&mut data, &mut ctx, None).unwrap();
+        data
+    });
+}
+
+/// many independent top-level calls
+#[bench]
+fn bench_eval_flat(b: &mut test::Bencher) {
+    let mut input = String::from("\\def-lazy(item 1 {<li>$0</li>})\n");
+    for i in 0..500 {
+        input += &format!("\\(item \\(add {} 1))\n", i);
+    }
+    bench_input(b, &input);
+}
+
+/// a single call whose expansion doubles in size on each level
+#[bench]
+fn bench_eval_nested(b: &mut test::Bencher) {
+    let mut input = String::from("\\def-lazy(l0 0 x)\n");
+    for i in 1..10 {
+        input += &format!("\\def-lazy(l{} 0 {{\\(l{1})\\(l{1})}})\n", i, i - 1);
+    }
+    input += "\\(l9)\n";
+    bench_input(b, &input);
+}
+
+/// calls which reference macros that are defined later
+#[bench]
+fn bench_eval_forward(b: &mut test::Bencher) {
+    let mut input = String::new();
+    for i in 0..100 {
+        input += &format!("\\(m{} a)\n", i);
+    }
+    for i in 0..100 {
+        input += &format!("\\def-lazy(m{} 1 {{[$0]}})\n", i);
+    }
+    bench_input(b, &input);
+}
+
+/// a large definition body, instantiated many times
+#[bench]
+fn bench_eval_large_body(b: &mut test::Bencher) {
+    let mut input = String::from("\\def-lazy(page 1 {");
+    for i in 0..100 {
+        input += &format!("<p id=\"{}\">($0) lorem ipsum dolor sit amet</p>\n", i);
+    }
+    input += "})\n";
+    for i in 0..200 {
+        input += &format!("\\(page {})\n", i);
+    }
+    bench_input(b, &input);
+}
+
+/// a lambda with a large body, applied to each element of a list
+#[bench]
+fn bench_eval_foreach_lambda(b: &mut test::Bencher) {
+    let mut input = String::from("\\foreach((");
+    for i in 0..500 {
+        input += &format!("{} ", i);
+    }
+    input += ") \\lambda(1 {";
+    for i in 0..50 {
+        input += &format!("<td class=\"c{}\">$0</td>", i);
+    }
+    input += "}))\n";
+    bench_input(b, &input);
+}
+
+/// the body of the `page` macro from [`bench_eval_large_body`]
+fn large_body() -> Node {
+    let mut input = String::from("\\(page {");
+    for i in 0..100 {
+        input += &format!("<p id=\"{}\">($0) lorem ipsum dolor sit amet</p>\n", i);
+    }
+    input += "})";
+    match parse_toplevel(input.as_bytes(), &ParserOptions::default())
+        .unwrap()
+        .lift_ast()
+        .simplify()
+    {
+        Node::CmdEval { args, .. } => args.0.lift_ast().simplify(),
+        x => panic!("unexpected parser output: {:?}", x),
+    }
+}
+
+fn instance_args() -> CmdEvalArgs {
+    CmdEvalArgs(vec![Node::Constant {
+        non_space: true,
+        data: b"x".to_vec().into(),
+    }])
+}
+
+/// instantiation of a shared definition body (copy-on-substitute)
+#[bench]
+fn bench_instantiate_copy(b: &mut test::Bencher) {
+    let (body, args) = (large_body(), instance_args());
+    b.iter(|| body.apply_arguments(&args).unwrap());
+}
+
+/// instantiation by cloning the definition body and substituting in place
+#[bench]
+fn bench_instantiate_clone_inplace(b: &mut test::Bencher) {
+    let (body, args) = (large_body(), instance_args());
+    b.iter(|| {
+        let mut x = body.clone();
+        x.apply_arguments_inplace(&args).unwrap();
+        x
+    });
+}
@@ -1,8 +1,23 @@
 #![feature(test)]
 
-use crulz::ast::{compact_toplevel, Lift as _, Mangle as _, Node::*};
+use crulz::{
+    ast::{arena, compact_toplevel, Lift as _, Mangle as _, Node::*},
+    parser::{parse_toplevel, Options as ParserOptions},
+};
 extern crate test;
 
+/// a document with many constants and calls
+fn document() -> Vec<u8> {
+    let mut ret = String::new();
+    for i in 0..200 {
+        ret += &format!(
+            "\\(item {} {{<a href=\"#s{}\">section $0</a> with some text}})\n",
+            i, i
+        );
+    }
+    ret.into_bytes()
+}
+
 #[bench]
 fn bench_simplify(b: &mut test::Bencher) {
     let ast = vec![
@@ -60,3 +75,45 @@ fn bench_compact_tl(b: &mut test::Bencher) {
     .simplify();
     b.iter(|| compact_toplevel(ast.clone()));
 }
+
+#[bench]
+fn bench_simplify_arena(b: &mut test::Bencher) {
+    let text = |x: &[u8]| arena::Node::Constant {
+        non_space: true,
+        data: arena::Text::new(x.to_vec()),
+    };
+    let ast = vec![
+        text(b"a"),
+        text(b"b").lift_ast().lift_ast().lift_ast().lift_ast(),
+        text(b"c"),
+    ]
+    .lift_ast()
+    .lift_ast()
+    .lift_ast();
+    b.iter(|| ast.clone().simplify());
+}
+
+#[bench]
+fn bench_parse_ast(b: &mut test::Bencher) {
+    let (input, opts) = (document(), ParserOptions::default());
+    b.iter(|| parse_toplevel(&input, &opts).unwrap());
+}
+
+#[bench]
+fn bench_parse_arena(b: &mut test::Bencher) {
+    let (input, opts) = (arena::Arena::new(document()), ParserOptions::default());
+    b.iter(|| input.parse_toplevel(&opts).unwrap());
+}
+
+#[bench]
+fn bench_clone_simplify_ast(b: &mut test::Bencher) {
+    let ast = parse_toplevel(&document(), &ParserOptions::default()).unwrap();
+    b.iter(|| ast.clone().simplify());
+}
+
+#[bench]
+fn bench_clone_simplify_arena(b: &mut test::Bencher) {
+    let input = arena::Arena::new(document());
+    let ast = input.parse_toplevel(&ParserOptions::default()).unwrap();
+    b.iter(|| ast.clone().simplify());
+}
@@ -0,0 +1,849 @@
+//! an experimental AST representation, which avoids most of the allocations of
+//! [`ast::Node`](super::Node): constant text is stored as shared slices of the input
+//! buffer (the [`Arena`]), and constant command names are interned as [`Symbol`]s
+//!
+//! It is only built for the tests and the benchmarks (feature `nightly`, see
+//! `benches/mangle_ast.rs`), to compare parsing and mangling with [`ast::Node`](super::Node).
+//! The interpreter doesn't support it, thus the evaluation doesn't benefit from it.
+
+use super::{Argc, ArgumentsForm, GroupType, Lift, Mangle};
+use crate::parser::{self, Builder, Options as ParserOptions};
+use bstr::ByteSlice;
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    fmt,
+    hash::{Hash, Hasher},
+    io,
+    ops::{Deref, Range},
+    sync::Arc,
+};
+
+// === symbols
+
+/// a command name, symbols interned by the same [`Arena`] share their buffer,
+/// which makes comparing them cheap
+#[derive(Clone)]
+pub struct Symbol(Arc<[u8]>);
+
+impl Symbol {
+    /// creates a symbol which isn't interned
+    #[inline]
+    pub fn new(name: &[u8]) -> Self {
+        Symbol(name.into())
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl PartialEq for Symbol {
+    #[inline]
+    fn eq(&self, other: &Symbol) -> bool {
+        Arc::ptr_eq(&self.0, &other.0) || self.0 == other.0
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_bytes().as_bstr())
+    }
+}
+
+// === text
+
+/// a shared slice of constant text, cloning it doesn't copy the text
+#[derive(Clone)]
+pub struct Text {
+    buf: Arc<Vec<u8>>,
+    range: Range<usize>,
+}
+
+impl Text {
+    /// creates a text which has its own buffer
+    pub fn new(data: Vec<u8>) -> Self {
+        let range = 0..data.len();
+        Self {
+            buf: Arc::new(data),
+            range,
+        }
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[self.range.clone()]
+    }
+
+    /// appends `other`, which only copies something if `other` doesn't directly
+    /// follow `self` in the same buffer
+    pub fn append(&mut self, other: &Text) {
+        if other.range.is_empty() {
+            // nothing to do
+        } else if self.range.is_empty() {
+            *self = other.clone();
+        } else if Arc::ptr_eq(&self.buf, &other.buf) && self.range.end == other.range.start {
+            self.range.end = other.range.end;
+        } else {
+            match Arc::get_mut(&mut self.buf) {
+                // the buffer isn't shared, thus we can grow it
+                Some(buf) if self.range.end == buf.len() => {
+                    buf.extend_from_slice(other.as_bytes());
+                    self.range.end = buf.len();
+                }
+                _ => {
+                    let mut data = Vec::with_capacity(self.range.len() + other.range.len());
+                    data.extend_from_slice(self.as_bytes());
+                    data.extend_from_slice(other.as_bytes());
+                    *self = Text::new(data);
+                }
+            }
+        }
+    }
+}
+
+impl Default for Text {
+    #[inline]
+    fn default() -> Self {
+        Text::new(Vec::new())
+    }
+}
+
+impl Deref for Text {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl PartialEq for Text {
+    #[inline]
+    fn eq(&self, other: &Text) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for Text {}
+
+impl Hash for Text {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state)
+    }
+}
+
+impl fmt::Debug for Text {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_bytes().as_bstr())
+    }
+}
+
+// === nodes
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Node {
+    NullNode,
+
+    /// see [`ast::Node::Argument`](super::Node::Argument)
+    Argument {
+        indirection: usize,
+        index: Option<usize>,
+    },
+
+    /// see [`ast::Node::NamedArgument`](super::Node::NamedArgument)
+    NamedArgument {
+        indirection: usize,
+        name: Symbol,
+    },
+
+    /// see [`ast::Node::Arguments`](super::Node::Arguments)
+    Arguments {
+        indirection: usize,
+        form: ArgumentsForm,
+    },
+
+    /// a command evaluation with a constant command name
+    Call {
+        name: Symbol,
+        args: Args,
+    },
+
+    CmdEval {
+        cmd: VAN,
+        args: Args,
+    },
+
+    Constant {
+        non_space: bool,
+        data: Text,
+    },
+
+    Grouped {
+        typ: GroupType,
+        elems: VAN,
+    },
+
+    Lambda {
+        argc: Argc,
+        body: Box<Node>,
+    },
+}
+
+pub type VAN = Vec<Node>;
+
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Args(pub VAN);
+
+impl Default for Node {
+    #[inline(always)]
+    fn default() -> Self {
+        Node::NullNode
+    }
+}
+
+impl Node {
+    #[inline(always)]
+    pub(crate) fn is_space(&self) -> bool {
+        matches!(
+            self,
+            Node::NullNode
+                | Node::Constant {
+                    non_space: false,
+                    ..
+                }
+        )
+    }
+
+    /// converts this node into an [`ast::Node`](super::Node)
+    pub fn to_ast(&self) -> super::Node {
+        use super::{CmdEvalArgs, Node as N};
+        let van = |x: &[Node]| x.iter().map(Node::to_ast).collect();
+        match self {
+            Node::NullNode => N::NullNode,
+            Node::Argument { indirection, index } => N::Argument {
+                indirection: *indirection,
+                index: *index,
+            },
+            Node::NamedArgument { indirection, name } => N::NamedArgument {
+                indirection: *indirection,
+                name: name.as_bytes().into(),
+            },
+            Node::Arguments { indirection, form } => N::Arguments {
+                indirection: *indirection,
+                form: *form,
+            },
+            Node::Call { name, args } => N::CmdEval {
+                cmd: vec![N::Constant {
+                    non_space: true,
+                    data: name.as_bytes().into(),
+                }],
+                args: CmdEvalArgs(van(&args.0)),
+            },
+            Node::CmdEval { cmd, args } => N::CmdEval {
+                cmd: van(cmd),
+                args: CmdEvalArgs(van(&args.0)),
+            },
+            Node::Constant { non_space, data } => N::Constant {
+                non_space: *non_space,
+                data: data.as_bytes().into(),
+            },
+            Node::Grouped { typ, elems } => N::Grouped {
+                typ: *typ,
+                elems: van(elems),
+            },
+            Node::Lambda { argc, body } => N::Lambda {
+                argc: argc.clone(),
+                body: Arc::new(body.to_ast()),
+            },
+        }
+    }
+}
+
+/// uses a [`Node::Call`] if the command name is constant,
+/// the name is interned if an `arena` is given
+fn make_cmd_eval(cmd: VAN, args: Args, arena: Option<&Arena>) -> Node {
+    match &cmd[..] {
+        [Node::Constant {
+            non_space: true,
+            data,
+        }] => Node::Call {
+            name: arena.map_or_else(|| Symbol::new(data), |x| x.intern(data)),
+            args,
+        },
+        _ => Node::CmdEval { cmd, args },
+    }
+}
+
+impl Lift for Node {
+    type LiftT = VAN;
+
+    #[inline(always)]
+    fn lift_ast(self) -> VAN {
+        vec![self]
+    }
+}
+
+impl Lift for VAN {
+    type LiftT = Node;
+
+    #[inline(always)]
+    fn lift_ast(self) -> Node {
+        Node::Grouped {
+            typ: GroupType::Dissolving,
+            elems: self,
+        }
+    }
+}
+
+impl Args {
+    /// see [`CmdEvalArgs::from_wsdelim`](super::CmdEvalArgs::from_wsdelim)
+    pub fn from_wsdelim(args: VAN) -> Self {
+        let mut it = args.into_iter();
+        Args(
+            std::iter::from_fn(move || {
+                let first = loop {
+                    let x = it.next()?;
+                    if !x.is_space() {
+                        break x;
+                    }
+                };
+                let mut res = std::iter::once(first)
+                    .chain(it.by_ref().take_while(|x| !x.is_space()))
+                    .collect::<VAN>()
+                    .lift_ast()
+                    .simplify();
+                if let Node::Grouped { ref mut typ, .. } = res {
+                    if *typ == GroupType::Dissolving {
+                        *typ = GroupType::Loose;
+                    }
+                }
+                Some(res)
+            })
+            .collect(),
+        )
+    }
+}
+
+// === arena
+
+/// the owner of an input buffer, the constants parsed from it point into it,
+/// and of the interned command names
+#[derive(Clone, Debug)]
+pub struct Arena {
+    input: Arc<Vec<u8>>,
+    symbols: RefCell<HashSet<Arc<[u8]>>>,
+}
+
+impl Arena {
+    #[inline]
+    pub fn new(input: Vec<u8>) -> Self {
+        Self {
+            input: Arc::new(input),
+            symbols: RefCell::default(),
+        }
+    }
+
+    /// returns the symbol for `name`, which shares its buffer with
+    /// all other symbols for `name` of this arena
+    pub fn intern(&self, name: &[u8]) -> Symbol {
+        let mut symbols = self.symbols.borrow_mut();
+        if let Some(x) = symbols.get(name) {
+            return Symbol(x.clone());
+        }
+        let name: Arc<[u8]> = name.into();
+        symbols.insert(name.clone());
+        Symbol(name)
+    }
+
+    #[inline]
+    pub fn input(&self) -> &[u8] {
+        &self.input
+    }
+
+    /// like [`parse_toplevel`](crate::parser::parse_toplevel)
+    pub fn parse_toplevel(&self, opts: &ParserOptions) -> Result<VAN, parser::Error<'_>> {
+        parser::parse_toplevel_with(&self.input, opts, self)
+    }
+
+    /// returns the text of `part`, which only points into
+    /// the input buffer if `part` is a part of it
+    pub fn text(&self, part: &[u8]) -> Text {
+        let start = (part.as_ptr() as usize).wrapping_sub(self.input.as_ptr() as usize);
+        if start <= self.input.len() && part.len() <= self.input.len() - start {
+            Text {
+                buf: self.input.clone(),
+                range: start..start + part.len(),
+            }
+        } else {
+            Text::new(part.to_vec())
+        }
+    }
+}
+
+impl Builder for Arena {
+    type Node = Node;
+
+    #[inline]
+    fn null(&self) -> Node {
+        Node::NullNode
+    }
+
+    #[inline]
+    fn constant(&self, non_space: bool, data: &[u8]) -> Node {
+        Node::Constant {
+            non_space,
+            data: self.text(data),
+        }
+    }
+
+    #[inline]
+    fn owned_constant(&self, non_space: bool, data: Vec<u8>) -> Node {
+        Node::Constant {
+            non_space,
+            data: Text::new(data),
+        }
+    }
+
+    #[inline]
+    fn argument(&self, indirection: usize, index: Option<usize>) -> Node {
+        Node::Argument { indirection, index }
+    }
+
+    #[inline]
+    fn named_argument(&self, indirection: usize, name: &[u8]) -> Node {
+        Node::NamedArgument {
+            indirection,
+            name: self.intern(name),
+        }
+    }
+
+    #[inline]
+    fn arguments(&self, indirection: usize, form: ArgumentsForm) -> Node {
+        Node::Arguments { indirection, form }
+    }
+
+    #[inline]
+    fn grouped(&self, typ: GroupType, elems: VAN) -> Node {
+        Node::Grouped { typ, elems }
+    }
+
+    #[inline]
+    fn cmd_eval(&self, cmd: VAN, args: VAN) -> Node {
+        make_cmd_eval(cmd, Args::from_wsdelim(args), Some(self))
+    }
+
+    #[inline]
+    fn is_space(&self, node: &Node) -> bool {
+        node.is_space()
+    }
+}
+
+// === mangle
+
+/// see [`ast::ArgumentsForm`](super::ArgumentsForm), all arguments are positional
+fn arguments_value(form: ArgumentsForm, xargs: &Args) -> Node {
+    match form {
+        ArgumentsForm::Remaining => Node::NullNode,
+        ArgumentsForm::Joined => Node::Grouped {
+            typ: GroupType::Loose,
+            elems: Vec::new(),
+        },
+        ArgumentsForm::Count => Node::Constant {
+            non_space: true,
+            data: Text::new(xargs.0.len().to_string().into_bytes()),
+        },
+    }
+}
+
+impl Mangle for Node {
+    type Args = Args;
+
+    fn write_to<W: io::Write + ?Sized>(&self, f: &mut W, opts: &ParserOptions) -> io::Result<()> {
+        use Node::*;
+        let parens = opts.strict_markers;
+        match self {
+            NullNode => {}
+            Constant { data, .. } => f.write_all(data)?,
+            Grouped { typ, elems } => {
+                let is_strict = *typ == GroupType::Strict;
+                if is_strict {
+                    f.write_all(&[parens.begin])?;
+                }
+                elems.write_to(f, opts)?;
+                if is_strict {
+                    f.write_all(&[parens.end])?;
+                }
+            }
+            Argument { indirection, index } => {
+                for _ in 0..=*indirection {
+                    f.write_all(&[opts.arg_sigil])?;
+                }
+                if let Some(i) = index {
+                    f.write_all(i.to_string().as_bytes())?;
+                }
+            }
+            NamedArgument { indirection, name } => {
+                for _ in 0..=*indirection {
+                    f.write_all(&[opts.arg_sigil])?;
+                }
+                f.write_all(name.as_bytes())?;
+            }
+            Arguments { indirection, form } => {
+                for _ in 0..=*indirection {
+                    f.write_all(&[opts.arg_sigil])?;
+                }
+                f.write_all(&[form.marker()])?;
+            }
+            Call { name, args } => {
+                f.write_all(&opts.escc)?;
+                f.write_all(&[parens.begin])?;
+                f.write_all(name.as_bytes())?;
+                args.write_to(f, opts)?;
+                f.write_all(&[parens.end])?;
+            }
+            CmdEval { cmd, args } => {
+                f.write_all(&opts.escc)?;
+                f.write_all(&[parens.begin])?;
+                cmd.write_to(f, opts)?;
+                args.write_to(f, opts)?;
+                f.write_all(&[parens.end])?;
+            }
+            Lambda { argc, body } => {
+                f.write_all(&opts.escc)?;
+                f.write_all(&[parens.begin])?;
+                f.write_all(b"lambda ")?;
+                f.write_all(argc.to_string().as_bytes())?;
+                f.write_all(b" ")?;
+                body.write_to(f, opts)?;
+                f.write_all(&[parens.end])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_complexity(&self) -> usize {
+        use Node::*;
+        match self {
+            NullNode => 0,
+            Argument { indirection, .. }
+            | NamedArgument { indirection, .. }
+            | Arguments { indirection, .. } => 3 + indirection,
+            // same as a `CmdEval` with a single constant
+            Call { name, args } => 2 + name.as_bytes().len() + args.get_complexity(),
+            CmdEval { cmd, args } => 1 + cmd.get_complexity() + args.get_complexity(),
+            Constant { data, .. } => 1 + data.len(),
+            Grouped { typ, elems } => {
+                (match *typ {
+                    GroupType::Dissolving => 0,
+                    GroupType::Loose => 1,
+                    GroupType::Strict => 2,
+                }) + elems.get_complexity()
+            }
+            Lambda { body, .. } => 2 + body.get_complexity(),
+        }
+    }
+
+    fn simplify(mut self) -> Self {
+        use Node::*;
+        super::while_cplx_changes(&mut self, |this| {
+            match this {
+                Grouped {
+                    ref mut typ,
+                    ref mut elems,
+                } => match elems.len() {
+                    0 => {
+                        if *typ != GroupType::Strict {
+                            *this = NullNode;
+                            return false;
+                        }
+                    }
+                    1 => {
+                        let y = elems[0].take().simplify();
+                        if *typ != GroupType::Strict {
+                            *this = y;
+                        } else if let Grouped {
+                            typ: GroupType::Dissolving,
+                            elems: z,
+                        } = y
+                        {
+                            *elems = z;
+                        } else if y == NullNode {
+                            elems.clear();
+                        } else {
+                            elems[0] = y;
+                        }
+                    }
+                    _ => elems.simplify_inplace(),
+                },
+                Call { ref mut args, .. } => args.simplify_inplace(),
+                CmdEval {
+                    ref mut cmd,
+                    ref mut args,
+                } => {
+                    cmd.simplify_inplace();
+                    args.simplify_inplace();
+                    if let [Constant {
+                        non_space: true, ..
+                    }] = &cmd[..]
+                    {
+                        *this = make_cmd_eval(cmd.take(), args.take(), None);
+                    }
+                }
+                Lambda { ref mut body, .. } => body.simplify_inplace(),
+                Constant { data, .. } if data.is_empty() => *this = NullNode,
+                _ => return false,
+            }
+            true
+        });
+        self
+    }
+
+    fn apply_arguments_inplace(&mut self, xargs: &Args) -> Result<(), usize> {
+        use Node::*;
+        match self {
+            Argument {
+                indirection: 0,
+                index: Some(index),
+            } => {
+                *self = match xargs.0.get(*index) {
+                    Some(x) => x.clone(),
+                    None => return Err(*index),
+                };
+            }
+            Argument {
+                indirection: 0,
+                index: None,
+            } => {}
+            Argument {
+                ref mut indirection,
+                ..
+            }
+            | NamedArgument {
+                ref mut indirection,
+                ..
+            }
+            | Arguments {
+                ref mut indirection,
+                ..
+            } if *indirection != 0 => *indirection -= 1,
+            Arguments { form, .. } => *self = arguments_value(*form, xargs),
+
+            Grouped { ref mut elems, .. } => elems.apply_arguments_inplace(xargs)?,
+            Call { ref mut args, .. } => args.apply_arguments_inplace(xargs)?,
+            CmdEval {
+                ref mut cmd,
+                ref mut args,
+            } => {
+                cmd.apply_arguments_inplace(xargs)?;
+                args.apply_arguments_inplace(xargs)?;
+            }
+            Lambda { ref mut body, .. } => body.apply_arguments_inplace(xargs)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn apply_arguments(&self, xargs: &Args) -> Result<Self, usize> {
+        use Node::*;
+        Ok(match self {
+            Argument {
+                indirection: 0,
+                index: Some(index),
+            } => xargs.0.get(*index).cloned().ok_or(*index)?,
+            Argument { indirection, index } if *indirection != 0 => Argument {
+                indirection: indirection - 1,
+                index: *index,
+            },
+            NamedArgument { indirection, name } if *indirection != 0 => NamedArgument {
+                indirection: indirection - 1,
+                name: name.clone(),
+            },
+            Arguments {
+                indirection: 0,
+                form,
+            } => arguments_value(*form, xargs),
+            Arguments { indirection, form } => Arguments {
+                indirection: indirection - 1,
+                form: *form,
+            },
+            Grouped { typ, elems } => Grouped {
+                typ: *typ,
+                elems: elems.apply_arguments(xargs)?,
+            },
+            Call { name, args } => Call {
+                name: name.clone(),
+                args: args.apply_arguments(xargs)?,
+            },
+            CmdEval { cmd, args } => CmdEval {
+                cmd: cmd.apply_arguments(xargs)?,
+                args: args.apply_arguments(xargs)?,
+            },
+            Lambda { argc, body } => Lambda {
+                argc: argc.clone(),
+                body: Box::new(body.apply_arguments(xargs)?),
+            },
+            _ => self.clone(),
+        })
+    }
+
+    fn curry2_inplace(&mut self, xargs: &Args) {
+        use Node::*;
+        match self {
+            Argument {
+                indirection: 0,
+                index: Some(index),
+            } => {
+                *self = match xargs.0.get(*index) {
+                    Some(x) => x.clone(),
+                    None => Argument {
+                        indirection: 0,
+                        index: Some(*index - xargs.0.len()),
+                    },
+                };
+            }
+            Grouped { ref mut elems, .. } => elems.curry2_inplace(xargs),
+            Call { ref mut args, .. } => args.curry2_inplace(xargs),
+            CmdEval {
+                ref mut cmd,
+                ref mut args,
+            } => {
+                cmd.curry2_inplace(xargs);
+                args.curry2_inplace(xargs);
+            }
+            // ignore sub-lambdas
+            _ => {}
+        }
+    }
+}
+
+impl Mangle for VAN {
+    type Args = Args;
+
+    fn write_to<W: io::Write + ?Sized>(&self, f: &mut W, opts: &ParserOptions) -> io::Result<()> {
+        for i in self {
+            i.write_to(f, opts)?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn get_complexity(&self) -> usize {
+        self.iter().map(Mangle::get_complexity).sum()
+    }
+
+    fn simplify(self) -> Self {
+        use Node::*;
+        let mut ret = VAN::with_capacity(self.len());
+        let mut it = self.into_iter().map(Mangle::simplify);
+        let mut litem = match it.next() {
+            Some(x) => x,
+            None => return VAN::new(),
+        };
+        for mut citem in it {
+            match (&mut litem, &mut citem) {
+                (_, NullNode) => {}
+                (
+                    Constant {
+                        non_space,
+                        ref mut data,
+                    },
+                    Constant {
+                        non_space: ins2,
+                        data: ref y,
+                    },
+                ) if non_space == ins2 => data.append(y),
+                (
+                    Grouped {
+                        typ: GroupType::Dissolving,
+                        ref mut elems,
+                    },
+                    Grouped {
+                        typ: GroupType::Dissolving,
+                        elems: ref mut y,
+                    },
+                ) => elems.append(y),
+                (a, b) => ret.push(std::mem::replace(a, b.take())),
+            }
+        }
+        ret.push(litem);
+        ret
+    }
+
+    fn apply_arguments_inplace(&mut self, args: &Args) -> Result<(), usize> {
+        for i in self.iter_mut() {
+            i.apply_arguments_inplace(args)?;
+        }
+        Ok(())
+    }
+
+    fn apply_arguments(&self, args: &Args) -> Result<Self, usize> {
+        self.iter().map(|i| i.apply_arguments(args)).collect()
+    }
+
+    fn curry2_inplace(&mut self, args: &Args) {
+        for i in self.iter_mut() {
+            i.curry2_inplace(args);
+        }
+    }
+}
+
+impl Mangle for Args {
+    type Args = Args;
+
+    fn write_to<W: io::Write + ?Sized>(&self, f: &mut W, opts: &ParserOptions) -> io::Result<()> {
+        for i in &self.0 {
+            f.write_all(b" ")?;
+            i.write_to(f, opts)?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn get_complexity(&self) -> usize {
+        self.0.get_complexity()
+    }
+
+    fn simplify(self) -> Self {
+        Args(
+            self.0
+                .into_iter()
+                .map(Mangle::simplify)
+                .flat_map(|i| match i {
+                    Node::NullNode => vec![],
+                    Node::Grouped {
+                        typ: GroupType::Dissolving,
+                        elems,
+                    } => elems,
+                    _ => vec![i],
+                })
+                .collect(),
+        )
+    }
+
+    #[inline]
+    fn apply_arguments_inplace(&mut self, args: &Args) -> Result<(), usize> {
+        self.0.apply_arguments_inplace(args)
+    }
+
+    #[inline]
+    fn apply_arguments(&self, args: &Args) -> Result<Self, usize> {
+        self.0.apply_arguments(args).map(Args)
+    }
+
+    #[inline]
+    fn curry2_inplace(&mut self, args: &Args) {
+        self.0.curry2_inplace(args)
+    }
+}
@@ -1,12 +1,23 @@
-use super::{CmdEvalArgs, GroupType, Node as ASTNode, VAN};
+use super::{ArgumentsForm, CmdEvalArgs, GroupType, Node as ASTNode, Param, VAN};
+use crate::parser::Options as ParserOptions;
 use delegate_attr::delegate;
-use itertools::Itertools;
+use std::{io, sync::Arc};
 
 // do NOT "use ASTNode::*;" here, because sometimes we want to "use ASTNodeClass::*;"
 
 pub trait Mangle: Default {
+    /// the arguments which can be applied to this AST
+    type Args;
+
     /// transform this AST into a byte string, outputs into `$f`
-    fn fmt(&self, f: &mut Vec<u8>, escc: u8);
+    #[inline]
+    fn fmt(&self, f: &mut Vec<u8>, opts: &ParserOptions) {
+        self.write_to(f, opts)
+            .expect("writing into a Vec<u8> doesn't fail")
+    }
+
+    /// like [`Mangle::fmt`], but outputs into any writer
+    fn write_to<W: io::Write + ?Sized>(&self, f: &mut W, opts: &ParserOptions) -> io::Result<()>;
 
     /// helper for [`Mangle::simplify`] and [`interp::eval`](crate::interp::eval)
     fn get_complexity(&self) -> usize;
@@ -32,58 +43,87 @@ pub trait Mangle: Default {
     ///
     /// # Return value
     /// * `Err(idx)`: the first applied index which wasn't present in 'args'
-    fn apply_arguments_inplace(&mut self, args: &CmdEvalArgs) -> Result<(), usize>;
+    fn apply_arguments_inplace(&mut self, args: &Self::Args) -> Result<(), usize>;
+
+    /// like [`Mangle::apply_arguments_inplace`], but leaves `self` untouched and builds
+    /// the instantiated copy in a single pass, which avoids cloning shared definitions
+    /// before the substitution
+    ///
+    /// # Return value
+    /// * `Err(idx)`: the first applied index which wasn't present in 'args'
+    fn apply_arguments(&self, args: &Self::Args) -> Result<Self, usize>;
 
     /// helper function for `crate::ast::Node::curry_inplace`
     #[doc(hidden)]
-    fn curry2_inplace(&mut self, args: &CmdEvalArgs);
+    fn curry2_inplace(&mut self, args: &Self::Args);
 }
 
 impl Mangle for ASTNode {
-    fn fmt(&self, f: &mut Vec<u8>, escc: u8) {
+    type Args = CmdEvalArgs;
+
+    fn write_to<W: io::Write + ?Sized>(&self, f: &mut W, opts: &ParserOptions) -> io::Result<()> {
         use ASTNode::*;
+        let parens = opts.strict_markers;
         match self {
             NullNode => {}
-            Constant { data, .. } => f.extend_from_slice(&data[..]),
+            Constant { data, .. } => f.write_all(&data[..])?,
             Grouped { typ, elems } => {
-                let parens = *typ == GroupType::Strict;
-                if parens {
-                    f.push(b'(');
+                let is_strict = *typ == GroupType::Strict;
+                if is_strict {
+                    f.write_all(&[parens.begin])?;
                 }
-                elems.fmt(f, escc);
-                if parens {
-                    f.push(b')');
+                elems.write_to(f, opts)?;
+                if is_strict {
+                    f.write_all(&[parens.end])?;
                 }
             }
             Argument { indirection, index } => {
-                f.extend(std::iter::repeat(b'$').take(indirection + 1));
+                for _ in 0..=*indirection {
+                    f.write_all(&[opts.arg_sigil])?;
+                }
                 if let Some(i) = index {
-                    f.extend_from_slice(i.to_string().as_bytes());
+                    f.write_all(i.to_string().as_bytes())?;
                 }
             }
+            NamedArgument { indirection, name } => {
+                for _ in 0..=*indirection {
+                    f.write_all(&[opts.arg_sigil])?;
+                }
+                f.write_all(name)?;
+            }
+            Arguments { indirection, form } => {
+                for _ in 0..=*indirection {
+                    f.write_all(&[opts.arg_sigil])?;
+                }
+                f.write_all(&[form.marker()])?;
+            }
             CmdEval { cmd, args } => {
-                f.push(escc);
-                f.push(b'(');
-                cmd.fmt(f, escc);
-                args.fmt(f, escc);
-                f.push(b')');
+                f.write_all(&opts.escc)?;
+                f.write_all(&[parens.begin])?;
+                cmd.write_to(f, opts)?;
+                args.write_to(f, opts)?;
+                f.write_all(&[parens.end])?;
             }
             Lambda { argc, body } => {
-                f.push(escc);
-                f.extend_from_slice(b"(lambda ");
-                f.extend_from_slice(argc.to_string().as_bytes());
-                f.push(b' ');
-                body.fmt(f, escc);
-                f.push(b')');
+                f.write_all(&opts.escc)?;
+                f.write_all(&[parens.begin])?;
+                f.write_all(b"lambda ")?;
+                f.write_all(argc.to_string().as_bytes())?;
+                f.write_all(b" ")?;
+                body.write_to(f, opts)?;
+                f.write_all(&[parens.end])?;
             }
         }
+        Ok(())
     }
 
     fn get_complexity(&self) -> usize {
         use ASTNode::*;
         match &self {
             NullNode => 0,
-            Argument { indirection, .. } => 3 + indirection,
+            Argument { indirection, .. }
+            | NamedArgument { indirection, .. }
+            | Arguments { indirection, .. } => 3 + indirection,
             CmdEval { cmd, args } => 1 + cmd.get_complexity() + args.get_complexity(),
             Constant { data, .. } => 1 + data.len(),
             Grouped { typ, elems } => {
@@ -139,7 +179,7 @@ impl Mangle for ASTNode {
                     cmd.simplify_inplace();
                     args.simplify_inplace();
                 }
-                Lambda { ref mut body, .. } => body.simplify_inplace(),
+                Lambda { ref mut body, .. } => Arc::make_mut(body).simplify_inplace(),
                 Constant { data, .. } if data.is_empty() => *this = NullNode,
                 _ => return false,
             }
@@ -153,23 +193,33 @@ impl Mangle for ASTNode {
         match self {
             Argument {
                 indirection: 0,
-                index,
+                index: Some(index),
             } => {
-                *self = match *index {
-                    Some(index) => match xargs.0.get(index) {
-                        Some(x) => x.clone(),
-                        None => return Err(index),
-                    },
-                    None => Constant {
-                        non_space: true,
-                        data: vec![b'$'].into(),
-                    },
+                *self = match xargs.0.get(*index) {
+                    Some(x) => x.clone(),
+                    None => return Err(*index),
                 };
             }
+            // index-less arguments are kept as-is, they're formatted as a literal sigil
+            Argument {
+                indirection: 0,
+                index: None,
+            } => {}
             Argument {
                 ref mut indirection,
                 ..
-            } => *indirection -= 1,
+            }
+            | NamedArgument {
+                ref mut indirection,
+                ..
+            }
+            | Arguments {
+                ref mut indirection,
+                ..
+            } if *indirection != 0 => *indirection -= 1,
+            // the parameter names are unknown here, see `ASTNode::apply_call_arguments`
+            NamedArgument { .. } => {}
+            Arguments { form, .. } => *self = arguments_value(*form, &xargs.0, xargs.len()),
 
             Grouped { ref mut elems, .. } => elems.apply_arguments_inplace(xargs)?,
             CmdEval {
@@ -179,31 +229,30 @@ impl Mangle for ASTNode {
                 cmd.apply_arguments_inplace(xargs)?;
                 args.apply_arguments_inplace(xargs)?;
             }
-            Lambda { ref mut body, .. } => body.apply_arguments_inplace(xargs)?,
+            Lambda { ref mut body, .. } => Arc::make_mut(body).apply_arguments_inplace(xargs)?,
             _ => {}
         }
         Ok(())
     }
 
+    #[inline]
+    fn apply_arguments(&self, xargs: &CmdEvalArgs) -> Result<Self, usize> {
+        self.apply_call_arguments(&xargs.0, xargs.len(), &[])
+    }
+
     #[doc(hidden)]
     fn curry2_inplace(&mut self, xargs: &CmdEvalArgs) {
         use ASTNode::*;
         match self {
             Argument {
                 indirection: 0,
-                index,
+                index: Some(index),
             } => {
-                *self = match *index {
-                    Some(index) => match xargs.0.get(index) {
-                        Some(x) => x.clone(),
-                        None => Argument {
-                            indirection: 0,
-                            index: Some(index - xargs.len()),
-                        },
-                    },
-                    None => Constant {
-                        non_space: true,
-                        data: vec![b'$'].into(),
+                *self = match xargs.0.get(*index) {
+                    Some(x) => x.clone(),
+                    None => Argument {
+                        indirection: 0,
+                        index: Some(*index - xargs.len()),
                     },
                 };
             }
@@ -224,10 +273,13 @@ impl Mangle for ASTNode {
 }
 
 impl Mangle for VAN {
-    fn fmt(&self, f: &mut Vec<u8>, escc: u8) {
+    type Args = CmdEvalArgs;
+
+    fn write_to<W: io::Write + ?Sized>(&self, f: &mut W, opts: &ParserOptions) -> io::Result<()> {
         for i in self {
-            i.fmt(f, escc);
+            i.write_to(f, opts)?;
         }
+        Ok(())
     }
 
     #[inline]
@@ -288,6 +340,17 @@ impl Mangle for VAN {
         Ok(())
     }
 
+    fn apply_arguments(&self, args: &CmdEvalArgs) -> Result<Self, usize> {
+        let call = CallArgs {
+            xargs: &args.0,
+            fixed: args.len(),
+            params: &[],
+        };
+        let mut err = None;
+        let ret = substituted_van(self, &call, &mut err);
+        err.map_or(Ok(ret), Err)
+    }
+
     fn curry2_inplace(&mut self, args: &CmdEvalArgs) {
         for i in self.iter_mut() {
             i.curry2_inplace(args);
@@ -296,11 +359,14 @@ impl Mangle for VAN {
 }
 
 impl Mangle for CmdEvalArgs {
-    fn fmt(&self, f: &mut Vec<u8>, escc: u8) {
+    type Args = CmdEvalArgs;
+
+    fn write_to<W: io::Write + ?Sized>(&self, f: &mut W, opts: &ParserOptions) -> io::Result<()> {
         for i in &self.0 {
-            f.push(b' ');
-            i.fmt(f, escc);
+            f.write_all(b" ")?;
+            i.write_to(f, opts)?;
         }
+        Ok(())
     }
 
     fn simplify(self) -> Self {
@@ -323,60 +389,176 @@ impl Mangle for CmdEvalArgs {
     #[delegate(self.0)]
     fn apply_arguments_inplace(&mut self, args: &CmdEvalArgs) -> Result<(), usize> {}
 
+    #[inline]
+    fn apply_arguments(&self, args: &CmdEvalArgs) -> Result<Self, usize> {
+        self.0.apply_arguments(args).map(CmdEvalArgs)
+    }
+
     #[delegate(self.0)]
     fn curry2_inplace(&mut self, args: &CmdEvalArgs) {}
 }
 
+impl ASTNode {
+    /// like [`Mangle::apply_arguments`], but only the first `fixed` arguments are
+    /// positional, the remaining ones are also accessible via `$@` and `$*`,
+    /// and `$name` is replaced by the argument of the parameter `name` of `params`
+    pub fn apply_call_arguments(
+        &self,
+        xargs: &[ASTNode],
+        fixed: usize,
+        params: &[Param],
+    ) -> Result<Self, usize> {
+        let call = CallArgs {
+            xargs,
+            fixed,
+            params,
+        };
+        let mut err = None;
+        let ret = substituted(self, &call, &mut err);
+        err.map_or(Ok(ret), Err)
+    }
+}
+
+/// the arguments which are substituted by [`ASTNode::apply_call_arguments`]
+struct CallArgs<'a> {
+    xargs: &'a [ASTNode],
+    fixed: usize,
+    params: &'a [Param],
+}
+
+/// the value of `$@`, `$*` or `$#`
+fn arguments_value(form: ArgumentsForm, xargs: &[ASTNode], fixed: usize) -> ASTNode {
+    let rest = xargs.get(fixed..).unwrap_or_default();
+    match form {
+        ArgumentsForm::Remaining => ASTNode::Grouped {
+            typ: GroupType::Dissolving,
+            elems: rest.to_vec(),
+        },
+        ArgumentsForm::Joined => {
+            let mut elems = VAN::with_capacity(2 * rest.len());
+            for (n, i) in rest.iter().enumerate() {
+                if n != 0 {
+                    elems.push(ASTNode::Constant {
+                        non_space: false,
+                        data: " ".into(),
+                    });
+                }
+                elems.push(i.clone());
+            }
+            ASTNode::Grouped {
+                typ: GroupType::Loose,
+                elems,
+            }
+        }
+        ArgumentsForm::Count => ASTNode::Constant {
+            non_space: true,
+            data: xargs.len().to_string().into(),
+        },
+    }
+}
+
+/// backend of [`Mangle::apply_arguments`], records the first missing index in `err`
+/// instead of returning a `Result` from each node, which is significantly faster
+fn substituted(node: &ASTNode, call: &CallArgs<'_>, err: &mut Option<usize>) -> ASTNode {
+    use ASTNode::*;
+    match node {
+        Argument {
+            indirection: 0,
+            index: Some(index),
+        } => match call.xargs.get(*index) {
+            Some(x) => x.clone(),
+            None => {
+                err.get_or_insert(*index);
+                NullNode
+            }
+        },
+        Argument { indirection, index } if *indirection != 0 => Argument {
+            indirection: indirection - 1,
+            index: *index,
+        },
+        NamedArgument {
+            indirection: 0,
+            name,
+        } => match call.params.iter().position(|i| i.name == *name) {
+            Some(index) => match call.xargs.get(index) {
+                Some(x) => x.clone(),
+                None => {
+                    err.get_or_insert(index);
+                    NullNode
+                }
+            },
+            // unknown names are kept as-is, like constants
+            None => node.clone(),
+        },
+        NamedArgument { indirection, name } => NamedArgument {
+            indirection: indirection - 1,
+            name: name.clone(),
+        },
+        Arguments { indirection, form } => match indirection {
+            0 => arguments_value(*form, call.xargs, call.fixed),
+            _ => Arguments {
+                indirection: indirection - 1,
+                form: *form,
+            },
+        },
+        Grouped { typ, elems } => Grouped {
+            typ: *typ,
+            elems: substituted_van(elems, call, err),
+        },
+        CmdEval { cmd, args } => CmdEval {
+            cmd: substituted_van(cmd, call, err),
+            args: CmdEvalArgs(substituted_van(&args.0, call, err)),
+        },
+        Lambda { argc, body } => Lambda {
+            argc: argc.clone(),
+            body: Arc::new(substituted(body, call, err)),
+        },
+        // index-less arguments are kept as-is, like constants
+        _ => node.clone(),
+    }
+}
+
+fn substituted_van(nodes: &[ASTNode], call: &CallArgs<'_>, err: &mut Option<usize>) -> VAN {
+    nodes.iter().map(|i| substituted(i, call, err)).collect()
+}
+
 pub fn compact_toplevel(x: VAN) -> VAN {
+    let mut ret = VAN::with_capacity(x.len());
+    for i in x {
+        compact_into(i.simplify(), &mut ret);
+    }
+    ret
+}
+
+/// backend of [`compact_toplevel`], appends the already simplified `node` to `ret`
+fn compact_into(node: ASTNode, ret: &mut VAN) {
+    use ASTNode::*;
     // we are at the top level, wo can inline non-strict groups
     // and then put all constants heaps into single constants
-    x.into_iter()
-        // 1. simplify
-        .map(Mangle::simplify)
-        // 1. inline non-strict groups
-        .flat_map(|i| match i {
-            ASTNode::NullNode => vec![],
-            ASTNode::Grouped { typ, elems } if typ != GroupType::Strict => compact_toplevel(elems),
-            _ => vec![i],
-        })
-        // 2. aggressive concat constant-after-constants
-        .peekable()
-        .batching(|it| {
-            use ASTNode::*;
-            let mut base = it.next()?;
-            match &mut base {
-                Constant {
-                    non_space,
-                    ref mut data,
-                } => {
-                    while let Some(Constant {
-                        non_space: ins2,
-                        data: ref y,
-                    }) = it.peek()
-                    {
-                        *non_space |= ins2;
-                        data.extend_from_slice(&y[..]);
-                        it.next();
-                    }
-                }
-                Grouped {
-                    typ: GroupType::Dissolving,
-                    ref mut elems,
-                } => {
-                    while let Some(Grouped {
-                        typ: GroupType::Dissolving,
-                        elems: ref y,
-                    }) = it.peek()
-                    {
-                        elems.extend_from_slice(&y[..]);
-                        it.next();
-                    }
-                    return Some(std::mem::take(elems));
-                }
-                _ => {}
+    match node {
+        NullNode => {}
+        // the elements of simplified groups are simplified too
+        Grouped { typ, elems } if typ != GroupType::Strict => {
+            for i in elems {
+                compact_into(i, ret);
+            }
+        }
+        Constant {
+            non_space: ins2,
+            data: y,
+        } => match ret.last_mut() {
+            Some(Constant {
+                non_space,
+                ref mut data,
+            }) => {
+                *non_space |= ins2;
+                data.extend_from_slice(&y[..]);
             }
-            Some(vec![base])
-        })
-        .flatten()
-        .collect()
+            _ => ret.push(Constant {
+                non_space: ins2,
+                data: y,
+            }),
+        },
+        _ => ret.push(node),
+    }
 }
@@ -1,7 +1,9 @@
 use delegate_attr::delegate;
 use serde::{Deserialize, Serialize};
-use std::borrow::Cow;
+use std::{borrow::Cow, fmt, sync::Arc};
 
+#[cfg(any(test, feature = "nightly"))]
+pub mod arena;
 mod mangle;
 mod tests;
 
@@ -15,6 +17,142 @@ pub enum GroupType {
     Dissolving,
 }
 
+/// the argument count of a macro or lambda, e.g. `2` or `2+`,
+/// or the named parameters of a definition, e.g. `(href text=$href)`
+#[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct Argc {
+    /// count of the positional arguments
+    pub fixed: usize,
+    /// if set, any count of additional arguments is accepted,
+    /// these are accessible via [`ArgumentsForm`]
+    pub variadic: bool,
+    /// the names of the positional arguments, if given, calls may pass
+    /// the arguments by name and omit those with defaults
+    pub params: Option<Arc<[Param]>>,
+}
+
+/// a named parameter of a definition, e.g. `href` or `text=$href`
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct Param {
+    pub name: bstr::BString,
+    /// substituted if the argument is omitted,
+    /// may refer to the preceding parameters
+    pub default: Option<Node>,
+}
+
+impl Argc {
+    #[inline]
+    pub const fn exact(fixed: usize) -> Self {
+        Self {
+            fixed,
+            variadic: false,
+            params: None,
+        }
+    }
+
+    pub fn named(params: Vec<Param>) -> Self {
+        Self {
+            fixed: params.len(),
+            variadic: false,
+            params: Some(params.into()),
+        }
+    }
+
+    /// the named parameters, empty if the arguments are only positional
+    #[inline]
+    pub fn params(&self) -> &[Param] {
+        self.params.as_deref().unwrap_or_default()
+    }
+
+    /// parses `n` or `n+`
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let (data, variadic) = match data.strip_suffix(b"+") {
+            Some(x) => (x, true),
+            None => (data, false),
+        };
+        Some(Self {
+            fixed: atoi::atoi(data)?,
+            variadic,
+            params: None,
+        })
+    }
+
+    /// checks if a call with `n` arguments is allowed
+    #[inline]
+    pub fn accepts(&self, n: usize) -> bool {
+        if self.variadic {
+            n >= self.fixed
+        } else {
+            n == self.fixed
+        }
+    }
+}
+
+impl From<usize> for Argc {
+    #[inline]
+    fn from(fixed: usize) -> Self {
+        Self::exact(fixed)
+    }
+}
+
+/// named parameters are formatted with the default parser options
+impl fmt::Display for Argc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(params) = &self.params {
+            f.write_str("(")?;
+            for (n, i) in params.iter().enumerate() {
+                if n != 0 {
+                    f.write_str(" ")?;
+                }
+                write!(f, "{}", i.name)?;
+                if let Some(x) = &i.default {
+                    let mut default = Vec::new();
+                    x.fmt(&mut default, &Default::default());
+                    write!(f, "={}", bstr::ByteSlice::as_bstr(&default[..]))?;
+                }
+            }
+            return f.write_str(")");
+        }
+        write!(f, "{}", self.fixed)?;
+        if self.variadic {
+            f.write_str("+")?;
+        }
+        Ok(())
+    }
+}
+
+/// the forms of [`Node::Arguments`], which refer to multiple arguments at once
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum ArgumentsForm {
+    /// `$@`: the arguments after the positional ones, spliced into the
+    /// surrounding argument list
+    Remaining,
+    /// `$*`: like `$@`, but as a single loose group, separated by spaces
+    Joined,
+    /// `$#`: the count of all arguments
+    Count,
+}
+
+impl ArgumentsForm {
+    /// the character after the sigils
+    pub fn marker(self) -> u8 {
+        match self {
+            ArgumentsForm::Remaining => b'@',
+            ArgumentsForm::Joined => b'*',
+            ArgumentsForm::Count => b'#',
+        }
+    }
+
+    pub fn from_marker(x: u8) -> Option<Self> {
+        Some(match x {
+            b'@' => ArgumentsForm::Remaining,
+            b'*' => ArgumentsForm::Joined,
+            b'#' => ArgumentsForm::Count,
+            _ => return None,
+        })
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub enum Node {
     NullNode,
@@ -22,10 +160,26 @@ pub enum Node {
     Argument {
         /// `= (count of '$'s) - 1`
         indirection: usize,
-        /// no given index means something like '$$.'
+        /// no given index means something like '$$.',
+        /// which results in a literal sigil once the indirection reaches zero
         index: Option<usize>,
     },
 
+    /// `$name`, refers to a named parameter (see [`Argc::params`]),
+    /// unknown names are kept as-is
+    NamedArgument {
+        /// `= (count of '$'s) - 1`
+        indirection: usize,
+        name: bstr::BString,
+    },
+
+    /// `$@`, `$*` or `$#`, substituted like [`Node::Argument`]
+    Arguments {
+        /// `= (count of '$'s) - 1`
+        indirection: usize,
+        form: ArgumentsForm,
+    },
+
     CmdEval {
         cmd: Vec<Node>,
         args: CmdEvalArgs,
@@ -42,8 +196,9 @@ pub enum Node {
     },
 
     Lambda {
-        argc: usize,
-        body: Box<Node>,
+        argc: Argc,
+        /// shared with the definition the lambda was made from, if any
+        body: Arc<Node>,
     },
 }
 
@@ -60,13 +215,14 @@ impl std::default::Default for Node {
 impl Node {
     #[inline(always)]
     pub(crate) fn is_space(&self) -> bool {
-        match self {
+        matches!(
+            self,
             NullNode
-            | Constant {
-                non_space: false, ..
-            } => true,
-            _ => false,
-        }
+                | Constant {
+                    non_space: false,
+                    ..
+                }
+        )
     }
 
     #[inline(always)]
@@ -91,8 +247,7 @@ impl Node {
                     let impc: Vec<_> = impc.map(Option::unwrap).collect();
                     Some(
                         impc.iter()
-                            .map(|i| i.as_ref().bytes())
-                            .flatten()
+                            .flat_map(|i| i.as_ref().bytes())
                             .collect::<Vec<_>>()
                             .into(),
                     )
@@ -119,9 +274,9 @@ impl Node {
             ref mut body,
         } = self
         {
-            if *argc != 0 {
-                *argc = argc.saturating_sub(xargs.len());
-                body.curry2_inplace(xargs);
+            if argc.fixed != 0 {
+                argc.fixed = argc.fixed.saturating_sub(xargs.len());
+                Arc::make_mut(body).curry2_inplace(xargs);
             }
         } else {
             self.curry2_inplace(xargs);
@@ -238,8 +393,8 @@ impl CmdEvalArgs {
 #[delegate(self.0)]
 #[rustfmt::skip]
 impl CmdEvalArgs {
-    pub fn iter(&self) -> std::slice::Iter<Node> { }
-    pub fn iter_mut(&mut self) -> std::slice::IterMut<Node> { }
+    pub fn iter(&self) -> std::slice::Iter<'_, Node> { }
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Node> { }
     pub fn len(&self) -> usize { }
     pub fn is_empty(&self) -> bool { }
 }
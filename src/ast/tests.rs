@@ -95,3 +95,134 @@ fn test_compact_tl() {
         }]
     );
 }
+
+#[test]
+fn test_apply_arguments() {
+    let body = vec![
+        Argument {
+            indirection: 0,
+            index: Some(0),
+        },
+        Lambda {
+            argc: 1.into(),
+            body: Arc::new(
+                vec![
+                    Argument {
+                        indirection: 1,
+                        index: Some(1),
+                    },
+                    Argument {
+                        indirection: 0,
+                        index: Some(0),
+                    },
+                ]
+                .lift_ast(),
+            ),
+        },
+    ]
+    .lift_ast();
+    let args = CmdEvalArgs(vec![
+        Constant {
+            non_space: true,
+            data: b"a".to_vec().into(),
+        },
+        Constant {
+            non_space: true,
+            data: b"b".to_vec().into(),
+        },
+    ]);
+    let mut expected = body.clone();
+    expected.apply_arguments_inplace(&args).unwrap();
+    assert_eq!(body.apply_arguments(&args), Ok(expected));
+    assert_eq!(body.apply_arguments(&CmdEvalArgs(vec![])), Err(0));
+}
+
+#[test]
+fn test_arena() {
+    use crate::parser::{parse_toplevel, Options};
+    let input = b"a \\(b c$0 {d e} (f)) g\\h(i $$1)\\(j$0 k)\\(lambda 1 $0 l)";
+    let opts = Options::default();
+    let arena = arena::Arena::new(input.to_vec());
+    let nodes = arena.parse_toplevel(&opts).unwrap();
+    let expected = parse_toplevel(input, &opts).unwrap();
+    assert_eq!(
+        nodes.iter().map(arena::Node::to_ast).collect::<VAN>(),
+        expected
+    );
+    assert!(matches!(nodes[1], arena::Node::Call { .. }));
+    assert!(matches!(nodes[4], arena::Node::CmdEval { .. }));
+
+    // constants point into the input buffer
+    let range = arena.input().as_ptr_range();
+    let arena::Node::Constant { data, .. } = &nodes[0] else {
+        panic!("unexpected node: {:?}", nodes[0]);
+    };
+    assert!(range.contains(&data.as_ptr()));
+
+    let simplified = nodes.clone().simplify();
+    assert_eq!(
+        simplified.iter().map(arena::Node::to_ast).collect::<VAN>(),
+        expected.clone().simplify()
+    );
+    let (mut a, mut b) = (Vec::new(), Vec::new());
+    simplified.fmt(&mut a, &opts);
+    expected.fmt(&mut b, &opts);
+    assert_eq!(a, b);
+
+    let args = arena::Args(vec![arena::Node::Constant {
+        non_space: true,
+        data: arena::Text::new(b"x".to_vec()),
+    }]);
+    let mut expected = expected.simplify();
+    expected
+        .apply_arguments_inplace(&CmdEvalArgs(vec![Constant {
+            non_space: true,
+            data: b"x".to_vec().into(),
+        }]))
+        .unwrap();
+    assert_eq!(
+        simplified
+            .apply_arguments(&args)
+            .unwrap()
+            .iter()
+            .map(arena::Node::to_ast)
+            .collect::<VAN>(),
+        expected
+    );
+}
+
+#[test]
+fn test_arena_text() {
+    let arena = arena::Arena::new(b"abcdef".to_vec());
+    let input = arena.input();
+    let mut x = arena.text(&input[..2]);
+    x.append(&arena.text(&input[2..4]));
+    // adjacent slices are merged without copying
+    assert_eq!(x.as_ptr(), input.as_ptr());
+    assert_eq!(&*x, b"abcd");
+    x.append(&arena.text(&input[5..]));
+    assert_eq!(&*x, b"abcdf");
+    assert_ne!(x.as_ptr(), input.as_ptr());
+    assert_eq!(&*arena.text(b"xyz"), b"xyz");
+}
+
+#[test]
+fn test_arena_symbols() {
+    let arena = arena::Arena::new(b"\\(a)\\(a b)".to_vec());
+    let nodes = arena.parse_toplevel(&Default::default()).unwrap();
+    let name = |x: &arena::Node| match x {
+        arena::Node::Call { name, .. } => name.clone(),
+        _ => panic!("unexpected node: {:?}", x),
+    };
+    let (a, b) = (name(&nodes[0]), name(&nodes[1]));
+    // the names are interned per arena
+    assert_eq!(a.as_bytes().as_ptr(), b.as_bytes().as_ptr());
+    assert_eq!(
+        a.as_bytes().as_ptr(),
+        arena.intern(b"a").as_bytes().as_ptr()
+    );
+    let other = arena::Arena::new(Vec::new()).intern(b"a");
+    assert_ne!(a.as_bytes().as_ptr(), other.as_bytes().as_ptr());
+    assert_eq!(a, other);
+    assert_ne!(a, arena::Symbol::new(b"b"));
+}
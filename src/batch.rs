@@ -0,0 +1,173 @@
+//! evaluation of multiple independent inputs on a thread pool
+
+use crate::{
+    ast::VAN,
+    interp::{eval, panic_message, EvalContext},
+    parser::{bytes2ast, capture_diagnostics},
+};
+use anyhow::Context;
+use std::{
+    collections::BTreeMap,
+    io::{self, Write as _},
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+};
+
+/// reads, parses and evaluates a single input
+fn eval_file(path: &Path, ctx: &mut EvalContext<'_>) -> Result<VAN, anyhow::Error> {
+    let input = ctx
+        .loader
+        .load(path)
+        .with_context(|| format!("unable to read file '{}'", path.display()))?;
+    let mut data = bytes2ast(path, &input, &ctx.opts)?;
+    eval(&mut data, ctx, None)?;
+    Ok(data)
+}
+
+/// evaluates each input in its own [fork](EvalContext::fork) of `ctx`,
+/// with up to `jobs` threads
+///
+/// `done` is called in the order of `inputs` (regardless of the order in which
+/// the evaluations finish) with the path, the used context and the result of each input.
+/// Panics during the evaluation of an input are reported as its error, and the parser
+/// diagnostics of each input are printed to stderr right before `done` is called for it.
+pub fn eval_files<'a>(
+    inputs: &[PathBuf],
+    ctx: &EvalContext<'a>,
+    jobs: usize,
+    mut done: impl FnMut(&Path, EvalContext<'a>, Result<VAN, anyhow::Error>),
+) {
+    // the contexts are forked up-front, because the tracer makes `EvalContext` `!Sync`
+    let forks: Vec<_> = inputs.iter().map(|_| ctx.fork()).collect();
+    let queue = Mutex::new(inputs.iter().zip(forks).enumerate());
+    let (tx, rx) = mpsc::channel();
+
+    std::thread::scope(|s| {
+        for _ in 0..jobs.clamp(1, inputs.len().max(1)) {
+            let tx = tx.clone();
+            let queue = &queue;
+            s.spawn(move || loop {
+                let next = queue.lock().unwrap().next();
+                let (idx, (path, mut ctx)) = match next {
+                    Some(x) => x,
+                    None => break,
+                };
+                let (res, diagnostics) = capture_diagnostics(|| {
+                    catch_unwind(AssertUnwindSafe(|| eval_file(path, &mut ctx)))
+                });
+                let res = res.unwrap_or_else(|e| {
+                    Err(anyhow::anyhow!("evaluation panicked: {}", panic_message(e)))
+                });
+                if tx.send((idx, ctx, res, diagnostics)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        // results which arrived before those of preceding inputs
+        let mut finished = BTreeMap::new();
+        let mut next = 0;
+        for (idx, ctx, res, diagnostics) in rx {
+            finished.insert(idx, (ctx, res, diagnostics));
+            while let Some((ctx, res, diagnostics)) = finished.remove(&next) {
+                io::stderr().write_all(&diagnostics).ok();
+                done(&inputs[next], ctx, res);
+                next += 1;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ast::{Mangle, Node as ASTNode},
+        interp::EvalError,
+        loader::MemoryFileLoader,
+    };
+    use std::{collections::HashMap, sync::Arc};
+
+    #[test]
+    fn test_eval_files() {
+        let mut loader = MemoryFileLoader::new();
+        loader.insert(
+            "/defs.crulz",
+            "\\suppress(\\def-lazy(greet 1 {Hello, $0!}))",
+        );
+        let mut inputs = Vec::new();
+        for i in 0..20 {
+            let path = PathBuf::from(format!("/page{}.crulz", i));
+            loader.insert(
+                &path,
+                format!("\\include(/defs.crulz)\\(greet {})\\def(x 0 y)", i),
+            );
+            inputs.push(path);
+        }
+        inputs.insert(3, PathBuf::from("/missing.crulz"));
+        loader.insert("/loop.crulz", "\\def-lazy(a 0 (\\(a)))\\(a)");
+        inputs.insert(7, PathBuf::from("/loop.crulz"));
+        loader.insert("/include.crulz", "\\include(/nonexistent.crulz)");
+        inputs.insert(9, PathBuf::from("/include.crulz"));
+        // failures of the other inputs don't affect the results of the following ones
+        loader.insert("/panic.crulz", "\\def(x invalid-argc y)");
+        inputs.insert(10, PathBuf::from("/panic.crulz"));
+
+        let mut ctx = EvalContext::new(Default::default(), HashMap::new());
+        ctx.loader = Arc::new(loader);
+        ctx.defs
+            .insert(b"shared".to_vec(), (0.into(), Arc::new(ASTNode::NullNode)));
+
+        for &jobs in &[1, 4] {
+            let mut results = Vec::new();
+            eval_files(&inputs, &ctx, jobs, |path, fctx, res| {
+                // the definitions of one input don't leak into the others
+                assert!(fctx.defs.contains_key(&b"shared"[..]));
+                assert_eq!(fctx.defs.contains_key(&b"x"[..]), res.is_ok());
+                let res = res.map(|data| {
+                    let mut out = Vec::new();
+                    data.fmt(&mut out, &fctx.opts);
+                    String::from_utf8(out).unwrap()
+                });
+                results.push((path.to_path_buf(), res));
+            });
+            assert_eq!(results.len(), inputs.len());
+            for ((path, res), input) in results.into_iter().zip(&inputs) {
+                assert_eq!(&path, input);
+                match path.to_str().unwrap() {
+                    "/missing.crulz" => assert!(res
+                        .unwrap_err()
+                        .to_string()
+                        .starts_with("unable to read file")),
+                    "/loop.crulz" => assert!(matches!(
+                        res.unwrap_err().downcast::<EvalError>(),
+                        Ok(EvalError::LimitExceeded(_))
+                    )),
+                    "/include.crulz" => assert!(matches!(
+                        res.unwrap_err().downcast::<EvalError>(),
+                        Ok(EvalError::Include(_))
+                    )),
+                    "/panic.crulz" => assert_eq!(
+                        res.unwrap_err().to_string(),
+                        "evaluation panicked: expected number or parameter list as argc"
+                    ),
+                    x => {
+                        let idx = &x["/page".len()..x.len() - ".crulz".len()];
+                        assert_eq!(res.unwrap(), format!("Hello, {}!", idx));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_send() {
+        fn assert_send<T: Send>() {}
+        fn assert_sync<T: Sync>() {}
+        assert_send::<EvalContext<'_>>();
+        assert_send::<crate::interp::DefinesMap>();
+        assert_sync::<crate::interp::DefinesMap>();
+    }
+}
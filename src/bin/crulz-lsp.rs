@@ -0,0 +1,25 @@
+use gumdrop::Options;
+
+#[derive(Debug, Options)]
+struct LspOptions {
+    #[options(help = "prints help information")]
+    help: bool,
+
+    #[options(no_short, help = "communicate over stdin and stdout (default)")]
+    stdio: bool,
+}
+
+fn main() {
+    let _opts = LspOptions::parse_args_default_or_exit();
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    let mut server = crulz::lsp::Server::new(Default::default());
+    match server.run(stdin.lock(), stdout.lock()) {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("crulz-lsp: ERROR: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use crate::{
+    ast::Node as ASTNode,
+    parser::{
+        parse_toplevel_recovering, report_error, report_errors, skip_comment, str_slice_between,
+        Error, Options, Parse,
+    },
+};
+use bstr::ByteSlice;
+
+// === formatter
+//
+// The formatter works directly on the source text instead of on the AST,
+// because the AST doesn't preserve the surface syntax (e.g. LaTeX-alike vs.
+// parenthesized command evaluations). Everything is copied verbatim,
+// except the white-space between the arguments of top-level
+// `suppress` blocks which span multiple lines.
+
+const SUPPRESS: &[u8] = b"suppress";
+
+/// indentation of the last (incomplete) line in `x`
+fn current_indent(x: &[u8]) -> &[u8] {
+    let line = match x.rfind_byte(b'\n') {
+        Some(pos) => &x[pos + 1..],
+        None => x,
+    };
+    let len = line
+        .iter()
+        .take_while(|&&i| i == b' ' || i == b'\t')
+        .count();
+    &line[..len]
+}
+
+/// splits a top-level `suppress` block into the prefix (escape + command name)
+/// and the argument list (without the trailing closing marker)
+fn split_suppress_block<'a>(block: &'a [u8], opts: &Options) -> Option<(&'a [u8], &'a [u8])> {
+    let rest = block.strip_prefix(&opts.escc[..])?;
+    let parens = opts.strict_markers;
+    let is_valid = match rest.strip_prefix(&[parens.begin][..]) {
+        // \(suppress ...)
+        Some(x) => x
+            .strip_prefix(SUPPRESS)?
+            .first()
+            .is_some_and(|&i| i.is_ascii_whitespace() || i == parens.end),
+        // \suppress(...)
+        None => rest.strip_prefix(SUPPRESS)?.first() == Some(&parens.begin),
+    };
+    let plen = opts.escc.len() + 1 + SUPPRESS.len();
+    if !is_valid || block.last() != Some(&parens.end) {
+        return None;
+    }
+    Some((&block[..plen], &block[plen..block.len() - 1]))
+}
+
+/// splits the white-space separated argument list into the source text of each argument
+fn split_args<'a>(mut data: &'a [u8], opts: &Options) -> Result<Vec<&'a [u8]>, Error<'a>> {
+    let mut ret = Vec::new();
+    let mut cur_start: Option<&[u8]> = None;
+    while !data.is_empty() {
+        if let Some(rest) = skip_comment(data, opts)? {
+            if cur_start.is_none() {
+                // comments which aren't part of an argument are kept on their own line
+                ret.push(str_slice_between(data, rest).trim_end_with(|i| i == '\n'));
+            }
+            data = rest;
+            continue;
+        }
+        let (rest, node) = ASTNode::parse(data, opts)?;
+        if node.is_space() {
+            if let Some(start) = cur_start.take() {
+                ret.push(str_slice_between(start, data));
+            }
+        } else if cur_start.is_none() {
+            cur_start = Some(data);
+        }
+        data = rest;
+    }
+    ret.extend(cur_start);
+    Ok(ret)
+}
+
+fn format_block<'a>(
+    ret: &mut Vec<u8>,
+    block: &'a [u8],
+    node: &ASTNode,
+    opts: &Options,
+) -> Result<(), Error<'a>> {
+    let is_suppress = match node {
+        ASTNode::CmdEval { cmd, .. } => cmd.len() == 1 && cmd[0].as_constant() == Some(SUPPRESS),
+        _ => false,
+    };
+    let (prefix, args) = match split_suppress_block(block, opts) {
+        Some(x) if is_suppress && x.1.contains(&b'\n') => x,
+        _ => {
+            ret.extend_from_slice(block);
+            return Ok(());
+        }
+    };
+
+    let indent = current_indent(ret).to_vec();
+    ret.extend_from_slice(prefix);
+    for i in split_args(args, opts)? {
+        ret.push(b'\n');
+        ret.extend_from_slice(&indent);
+        ret.extend_from_slice(b"  ");
+        ret.extend_from_slice(i);
+    }
+    ret.push(b'\n');
+    ret.extend_from_slice(&indent);
+    ret.push(opts.strict_markers.end);
+    Ok(())
+}
+
+/// formats the given source code, the result is guaranteed to parse
+/// to the same AST as the original code
+pub fn format<'a>(mut data: &'a [u8], opts: &Options) -> Result<Vec<u8>, Error<'a>> {
+    let mut ret = Vec::with_capacity(data.len());
+    while !data.is_empty() {
+        let (cstp, rest) = data.split_at(data.find(&opts.escc).unwrap_or(data.len()));
+        ret.extend_from_slice(cstp);
+        if rest.is_empty() {
+            break;
+        }
+        if let Some(rest2) = skip_comment(rest, opts)? {
+            ret.extend_from_slice(str_slice_between(rest, rest2));
+            data = rest2;
+            continue;
+        }
+        let (rest2, node) = ASTNode::parse(rest, opts)?;
+        format_block(&mut ret, str_slice_between(rest, rest2), &node, opts)?;
+        data = rest2;
+    }
+    Ok(ret)
+}
+
+/// reads and formats the given file
+///
+/// # Return value
+/// * `Ok(original, formatted)`
+pub fn format_file(
+    filename: &std::path::Path,
+    opts: &Options,
+) -> Result<(Vec<u8>, Vec<u8>), anyhow::Error> {
+    use anyhow::Context;
+
+    let input = std::fs::read(filename)
+        .with_context(|| format!("unable to read file '{}'", filename.display()))?;
+    let errs = parse_toplevel_recovering(&input, opts).1;
+    if !errs.is_empty() {
+        return Err(report_errors(filename, &input, opts, &errs));
+    }
+    let formatted = format(&input, opts).map_err(|e| report_error(filename, &input, opts, e))?;
+    Ok((input, formatted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_toplevel, ScopeMarkers};
+
+    fn check_fmt(input: &str, expected: &str) {
+        check_fmt_with(input, expected, &Options::default());
+    }
+
+    fn check_fmt_with(input: &str, expected: &str, opts: &Options) {
+        let formatted = format(input.as_bytes(), opts).unwrap();
+        assert_eq!(formatted.as_bstr(), expected.as_bytes().as_bstr());
+        // idempotency
+        assert_eq!(format(&formatted, opts).unwrap(), formatted);
+        // semantics are preserved
+        assert_eq!(
+            parse_toplevel(&formatted, opts).ok(),
+            parse_toplevel(input.as_bytes(), opts).ok()
+        );
+    }
+
+    #[test]
+    fn test_fmt_preserves_surface_syntax() {
+        let input = "\\def(a 2 $0$1)\\(a {x}  (y))\\\\ \\a(\\{ 1)\n";
+        check_fmt(input, input);
+    }
+
+    #[test]
+    fn test_fmt_suppress() {
+        check_fmt(
+            "a\n  \\(suppress \\(def a 0 x)\n \\(def b 1 {\n$0})   \\\n\\(def c 0 y))b",
+            "a\n  \\(suppress\n    \\(def a 0 x)\n    \\(def b 1 {\n$0})\n    \\(def c 0 y)\n  )b",
+        );
+        check_fmt(
+            "\\suppress(\\def(a 0 x)\n)",
+            "\\suppress(\n  \\def(a 0 x)\n)",
+        );
+        // single-line blocks are kept
+        check_fmt(
+            "\\(suppress \\(def a 0 x)  y)",
+            "\\(suppress \\(def a 0 x)  y)",
+        );
+    }
+
+    #[test]
+    fn test_fmt_comments() {
+        check_fmt(
+            "\\% a ) comment\n\\(suppress \\% (x\n\\(def a 0 x)\\%* y *%\\(def b 0 y)\n  \\%* z *% \\(def c 0 z))",
+            "\\% a ) comment\n\\(suppress\n  \\% (x\n  \\(def a 0 x)\\%* y *%\\(def b 0 y)\n  \\%* z *%\n  \\(def c 0 z)\n)",
+        );
+    }
+
+    #[test]
+    fn test_fmt_custom_markers() {
+        let opts = Options {
+            strict_markers: ScopeMarkers {
+                begin: b'<',
+                end: b'>',
+            },
+            ..Options::default()
+        };
+        check_fmt_with(
+            "\\<suppress \\<def a 0 (x)>\n\\<def b 0 y>>",
+            "\\<suppress\n  \\<def a 0 (x)>\n  \\<def b 0 y>\n>",
+            &opts,
+        );
+    }
+}
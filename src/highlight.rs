@@ -0,0 +1,121 @@
+use crate::parser::{
+    tokens::{tokenize, TokenKind},
+    Options,
+};
+use ansi_term::{Colour, Style};
+use std::borrow::Cow;
+
+// === syntax highlighting
+//
+// Both renderers only wrap the tokens in markup, the text itself is
+// copied verbatim (invalid UTF-8 is replaced).
+
+/// CSS class of the given token kind, plain text and white-space aren't highlighted
+fn class_of(kind: TokenKind) -> Option<&'static str> {
+    use TokenKind::*;
+    Some(match kind {
+        Escape => "crulz-escape",
+        CmdName => "crulz-cmd",
+        Argument { .. } | NamedArgument { .. } | Arguments { .. } => "crulz-arg",
+        GroupOpen(_) | GroupClose(_) => "crulz-group",
+        Comment => "crulz-comment",
+        Verbatim => "crulz-verbatim",
+        Error => "crulz-error",
+        Whitespace | Text => return None,
+    })
+}
+
+fn style_of(kind: TokenKind) -> Option<Style> {
+    use TokenKind::*;
+    Some(match kind {
+        Escape => Colour::Purple.bold(),
+        CmdName => Colour::Blue.bold(),
+        Argument { .. } | NamedArgument { .. } | Arguments { .. } => Colour::Yellow.normal(),
+        GroupOpen(_) | GroupClose(_) => Colour::Cyan.normal(),
+        Comment => Style::new().dimmed(),
+        Verbatim => Colour::Green.normal(),
+        Error => Colour::Red.underline(),
+        Whitespace | Text => return None,
+    })
+}
+
+fn escape_html(x: &str) -> Cow<'_, str> {
+    if !x.contains(&['&', '<', '>', '"'][..]) {
+        return x.into();
+    }
+    let mut ret = String::with_capacity(x.len() + 8);
+    for i in x.chars() {
+        match i {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            _ => ret.push(i),
+        }
+    }
+    ret.into()
+}
+
+/// renders the source code as HTML `pre` block, with a `span` per highlighted token
+pub fn to_html(data: &[u8], opts: &Options) -> String {
+    let mut ret = String::from("<pre class=\"crulz\">");
+    for i in tokenize(data, opts) {
+        let text = String::from_utf8_lossy(&data[i.span]);
+        let text = escape_html(&text);
+        match class_of(i.kind) {
+            Some(class) => {
+                ret += "<span class=\"";
+                ret += class;
+                ret += "\">";
+                ret += &text;
+                ret += "</span>";
+            }
+            None => ret += &text,
+        }
+    }
+    ret += "</pre>\n";
+    ret
+}
+
+/// renders the source code with ANSI escape codes, for terminals
+pub fn to_ansi(data: &[u8], opts: &Options) -> String {
+    let mut ret = String::with_capacity(data.len() * 2);
+    for i in tokenize(data, opts) {
+        let text = String::from_utf8_lossy(&data[i.span]);
+        match style_of(i.kind) {
+            Some(style) => ret += &style.paint(text).to_string(),
+            None => ret += &text,
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_html() {
+        let opts = Options::default();
+        assert_eq!(
+            to_html(b"<a> \\def(x 1 $0)\\% c\n", &opts),
+            "<pre class=\"crulz\">&lt;a&gt; <span class=\"crulz-escape\">\\</span>\
+             <span class=\"crulz-cmd\">def</span><span class=\"crulz-group\">(</span>x 1 \
+             <span class=\"crulz-arg\">$0</span><span class=\"crulz-group\">)</span>\
+             <span class=\"crulz-comment\">\\% c\n</span></pre>\n"
+        );
+    }
+
+    #[test]
+    fn test_ansi() {
+        let opts = Options::default();
+        assert_eq!(
+            to_ansi(b"a \\x", &opts),
+            format!(
+                "a {}{}",
+                Colour::Purple.bold().paint("\\"),
+                Colour::Blue.bold().paint("x")
+            )
+        );
+    }
+}
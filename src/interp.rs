@@ -1,10 +1,23 @@
 use crate::{
-    ast::{compact_toplevel, CmdEvalArgs, GroupType, Lift as _, Mangle, Node as ASTNode, VAN},
-    parser::Options as ParserOptions,
+    ast::{
+        compact_toplevel, Argc, CmdEvalArgs, GroupType, Lift as _, Mangle, Node as ASTNode, Param,
+        VAN,
+    },
+    loader::{FileLoader, StdFileLoader},
+    parser::{str_split_at_param_name, Options as ParserOptions},
+    profile::Profile,
+    trace::{TraceStep, Tracer},
 };
-#[cfg(feature = "compile")]
 use anyhow::Context;
-use std::{collections::HashMap, marker::PhantomData, path::Path};
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    io,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use {atoi::atoi, cfg_if::cfg_if, lazy_static::lazy_static};
 
 #[derive(Clone, Copy)]
@@ -18,18 +31,195 @@ pub enum BuiltInFn {
     Automatic(fn(&[ASTNode]) -> Option<ASTNode>),
 }
 
-type DefinesMap = HashMap<Vec<u8>, (usize, ASTNode)>;
-type ProcDefinesMap = HashMap<Vec<u8>, (Option<usize>, BuiltInFn)>;
+/// the user-defined macros with their argc and body,
+/// the bodies are immutable and only copied on instantiation
+pub type DefinesMap = HashMap<Vec<u8>, (Argc, Arc<ASTNode>)>;
+pub type ProcDefinesMap = HashMap<Vec<u8>, (Option<usize>, BuiltInFn)>;
 type CompilatesMap<'a> = HashMap<&'a Path, &'a Path>;
 
 pub const SUPPORTS_COMPILATION: bool = std::cfg!(feature = "compile");
 
+/// limits for the evaluation of untrusted input, `None` means unlimited
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    /// total count of command evaluations
+    pub max_steps: Option<usize>,
+    /// nesting depth of the evaluation, this guards against stack overflows
+    pub max_depth: Option<usize>,
+    /// size of the top-level output, measured by [`Mangle::get_complexity`]
+    pub max_output: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_steps: None,
+            max_depth: Some(256),
+            max_output: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum LimitKind {
+    #[error("evaluation step limit ({0})")]
+    Steps(usize),
+    #[error("recursion depth limit ({0})")]
+    Depth(usize),
+    #[error("output size limit ({0})")]
+    Output(usize),
+}
+
+fn fmt_chain(chain: &[Vec<u8>]) -> String {
+    use bstr::ByteSlice;
+    if chain.is_empty() {
+        return String::new();
+    }
+    let chain: Vec<_> = chain.iter().map(|i| i.to_str_lossy()).collect();
+    format!(" in macro chain: {}", chain.join(" -> "))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("{kind} exceeded{}", fmt_chain(.chain))]
+pub struct LimitExceeded {
+    pub kind: LimitKind,
+    /// names of the macros which were being evaluated, outermost first
+    pub chain: Vec<Vec<u8>>,
+}
+
+/// restrictions of file system accesses (`include` and compfiles)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sandbox {
+    /// no restrictions
+    Disabled,
+    /// all file system accesses are denied
+    DenyAll,
+    /// only files inside of the given root directories can be accessed,
+    /// after resolving all symlinks
+    AllowRoots(Vec<PathBuf>),
+}
+
+impl Default for Sandbox {
+    #[inline]
+    fn default() -> Self {
+        Sandbox::Disabled
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("access to '{}' denied by sandbox: {reason}", .path.display())]
+pub struct SandboxViolation {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+impl Sandbox {
+    /// checks if the file `path` can be accessed
+    ///
+    /// # Return value
+    /// * `Ok(resolved_path)`, which should be used to access the file,
+    ///   to avoid races with changed symlinks
+    pub fn check(&self, path: &Path, loader: &dyn FileLoader) -> Result<PathBuf, SandboxViolation> {
+        let violation = |reason: String| SandboxViolation {
+            path: path.to_path_buf(),
+            reason,
+        };
+        let roots = match self {
+            Sandbox::Disabled => return Ok(path.to_path_buf()),
+            Sandbox::DenyAll => return Err(violation("file system access is disabled".into())),
+            Sandbox::AllowRoots(roots) => roots,
+        };
+        // resolves '..' and symlinks
+        let resolved = loader
+            .canonicalize(path)
+            .map_err(|e| violation(format!("unable to resolve path: {}", e)))?;
+        if roots
+            .iter()
+            .filter_map(|i| loader.canonicalize(i).ok())
+            .any(|i| resolved.starts_with(i))
+        {
+            Ok(resolved)
+        } else {
+            Err(violation("path is outside of the allowed roots".into()))
+        }
+    }
+}
+
+/// an included file which couldn't be loaded or parsed
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("unable to include '{}': {reason}", .path.display())]
+pub struct IncludeError {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// a call of a definition with named parameters, whose arguments don't match these
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("invalid call of '{name}': {kind}")]
+pub struct ArgumentError {
+    pub name: bstr::BString,
+    pub kind: ArgumentErrorKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ArgumentErrorKind {
+    #[error("missing argument '{0}'")]
+    Missing(bstr::BString),
+    #[error("argument '{0}' given more than once")]
+    Duplicate(bstr::BString),
+    #[error("too many arguments")]
+    TooMany,
+}
+
+/// errors which abort the evaluation
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum EvalError {
+    #[error(transparent)]
+    LimitExceeded(#[from] LimitExceeded),
+    #[error(transparent)]
+    SandboxViolation(#[from] SandboxViolation),
+    #[error(transparent)]
+    InvalidArguments(#[from] ArgumentError),
+    #[error(transparent)]
+    Include(#[from] IncludeError),
+    #[error("evaluation aborted by the tracer")]
+    Aborted,
+    #[error("unable to write the output: {0}")]
+    Output(io::ErrorKind),
+}
+
 pub struct EvalContext<'a> {
     pub defs: DefinesMap,
     pub procdefs: ProcDefinesMap,
     pub opts: ParserOptions,
     #[cfg_attr(not(feature = "compile"), allow(unused))]
     pub comp_map: CompilatesMap<'a>,
+    pub limits: Limits,
+    pub sandbox: Sandbox,
+    /// used for all file system accesses of the evaluation, i.e. for
+    /// included files and compfiles
+    pub loader: Arc<dyn FileLoader>,
+    /// receives each expansion step, if set
+    pub tracer: Option<Box<dyn Tracer + 'a>>,
+    /// collects per-macro statistics, if set
+    pub profile: Option<Profile>,
+
+    steps: usize,
+    depth: usize,
+    /// names of the macros which are currently being evaluated
+    call_stack: Vec<Vec<u8>>,
+    /// once an error occurs, the evaluation is stopped
+    error: Option<EvalError>,
+    /// see [`TraceStep::location`]
+    location: Option<usize>,
+    /// incremented on each change of the definitions, pending evaluations
+    /// are only retried if it changed
+    generation: usize,
+    /// the result caches of the macros defined with `def-memo`,
+    /// keyed by the evaluated arguments
+    memo: HashMap<Vec<u8>, HashMap<VAN, ASTNode>>,
+    /// the generation for which the cached results are valid
+    memo_generation: usize,
 
     _non_exhaustive: PhantomData<()>,
 }
@@ -41,14 +231,17 @@ impl EvalContext<'_> {
         P: AsRef<Path> + ?Sized,
     {
         let compf = compf.as_ref();
-        let fh = readfilez::read_from_file(std::fs::File::open(compf))
+        let fh = self
+            .loader
+            .load(compf)
             .with_context(|| format!("Unable to open compfile '{}'", compf.display()))?;
-        let mut z = flate2::read::DeflateDecoder::new(fh.as_slice());
+        let mut z = flate2::read::DeflateDecoder::new(&fh[..]);
         let content: VAN = bincode::deserialize_from(&mut z)
             .with_context(|| format!("Unable to read compfile '{}'", compf.display()))?;
         let ins_defs: DefinesMap = bincode::deserialize_from(&mut z)
             .with_context(|| format!("Unable to read compfile '{}'", compf.display()))?;
-        self.defs.extend(ins_defs.into_iter());
+        self.defs.extend(ins_defs);
+        self.generation += 1;
         Ok(content)
     }
 
@@ -58,14 +251,18 @@ impl EvalContext<'_> {
         P: AsRef<Path> + ?Sized,
     {
         let compf = compf.as_ref();
-        let fh = std::fs::File::create(compf)
-            .with_context(|| format!("Failed to create compfile '{}'", compf.display()))?;
-        let mut z = flate2::write::DeflateEncoder::new(fh, flate2::Compression::default());
+        let mut z = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
         bincode::serialize_into(&mut z, content)
             .with_context(|| format!("Failed to write compfile '{}'", compf.display()))?;
-        bincode::serialize_into(&mut z, &self.defs)
+        // the definitions are only optimised for the compfile, because inlining
+        // assumes that they aren't changed anymore
+        let mut defs = self.defs.clone();
+        crate::optimize::optimize_defs(&mut defs, &self.procdefs, content);
+        bincode::serialize_into(&mut z, &defs)
             .with_context(|| format!("Failed to write compfile '{}'", compf.display()))?;
-        Ok(())
+        self.loader
+            .write(compf, &z.finish()?)
+            .with_context(|| format!("Failed to create compfile '{}'", compf.display()))
     }
 }
 
@@ -122,6 +319,7 @@ lazy_static! {
             b"curry"         => (args    , ctx) blti_curry,
             b"def"           => (args    , ctx) blti_def,
             b"def-lazy"      => (args    , ctx) blti_def_lazy,
+            b"def-memo"      => (args    , ctx) blti_def_memo,
             b"foreach"       => (args | 2, ctx) blti_foreach,
             b"fseq"          => (args    , ctx) blti_fseq,
             b"include"       => (args | 1, ctx) blti_include,
@@ -161,13 +359,13 @@ fn blti_curry(args: &mut CmdEvalArgs, ctx: &mut EvalContext<'_>) -> Option<ASTNo
             let mut args = args.clone();
             let mut ret = args.0.remove(0);
             if let ASTNode::Constant { ref data, .. } = &ret {
-                let cmd: &[u8] = &*data;
+                let cmd: &[u8] = data;
                 let (argc, body) = if let Some(a) = ctx.procdefs.get(cmd) {
                     // LIMITATION: we can't curry proc-fn's with variable argc
                     let a = a.0?;
                     (
-                        a,
-                        ASTNode::CmdEval {
+                        Argc::exact(a),
+                        Arc::new(ASTNode::CmdEval {
                             cmd: vec![ret],
                             args: (0..a)
                                 .map(|i| ASTNode::Argument {
@@ -175,15 +373,23 @@ fn blti_curry(args: &mut CmdEvalArgs, ctx: &mut EvalContext<'_>) -> Option<ASTNo
                                     index: Some(i),
                                 })
                                 .collect(),
-                        },
+                        }),
                     )
                 } else {
+                    // the body stays shared until it is curried
                     ctx.defs.get(cmd)?.clone()
                 };
-                ret = ASTNode::Lambda {
-                    argc,
-                    body: Box::new(body),
-                };
+                // LIMITATION: we can't curry variadic definitions
+                // or definitions with named parameters
+                if argc.variadic || argc.params.is_some() {
+                    return None;
+                }
+                ret = ASTNode::Lambda { argc, body };
+            }
+            if let ASTNode::Lambda { argc, .. } = &ret {
+                if argc.variadic {
+                    return None;
+                }
             }
             ret.curry_inplace(&args);
             Some(ret)
@@ -198,16 +404,22 @@ fn blti_def(args: &mut CmdEvalArgs, ctx: &mut EvalContext<'_>) -> Option<ASTNode
     }
     let varname = args[0].conv_to_constant()?.into_owned();
     let (argc, body) = if args.len() > 2 {
+        let argc = match &args[1] {
+            ASTNode::Grouped { elems, .. } => parse_params(elems),
+            x => Argc::parse(&x.conv_to_constant()?),
+        };
         (
-            atoi(&args[1].conv_to_constant()?).expect("expected number as argc"),
+            argc.expect("expected number or parameter list as argc"),
             args[2..].to_vec().lift_ast(),
         )
     } else if let ASTNode::Lambda { argc, ref body } = &args[1] {
-        (*argc, *(*body).clone())
+        (argc.clone(), ASTNode::clone(body))
     } else {
-        (0, args[1].clone())
+        (Argc::exact(0), args[1].clone())
     };
-    ctx.defs.insert(varname, (argc, body.simplify()));
+    ctx.memo.remove(&varname);
+    ctx.defs.insert(varname, (argc, Arc::new(body.simplify())));
+    ctx.generation += 1;
     Some(ASTNode::NullNode)
 }
 
@@ -219,18 +431,134 @@ fn blti_def_lazy(args: &mut CmdEvalArgs, ctx: &mut EvalContext<'_>) -> Option<AS
     let varname = unpack(&mut args[0], ctx)?;
     let definition = if args.len() == 2 {
         match &args[1] {
-            ASTNode::Lambda { argc, ref body } => (*argc, (*body).clone().simplify()),
-            x @ ASTNode::Constant { .. } => (0, x.clone().simplify()),
+            ASTNode::Lambda { argc, ref body } => (argc.clone(), ASTNode::clone(body).simplify()),
+            x @ ASTNode::Constant { .. } => (Argc::exact(0), x.clone().simplify()),
             _ => return None,
         }
     } else {
-        let argc: usize = atoi(&unpack(&mut args[1], ctx)?).expect("expected number as argc");
-        (argc, args[2..].to_vec().lift_ast().simplify())
+        // the defaults of named parameters are evaluated on each call
+        let argc = match &args[1] {
+            ASTNode::Grouped { elems, .. } => parse_params(elems),
+            _ => Argc::parse(&unpack(&mut args[1], ctx)?),
+        };
+        (
+            argc.expect("expected number or parameter list as argc"),
+            args[2..].to_vec().lift_ast().simplify(),
+        )
     };
-    ctx.defs.insert(varname, definition);
+    ctx.memo.remove(&varname);
+    ctx.defs
+        .insert(varname, (definition.0, Arc::new(definition.1)));
+    ctx.generation += 1;
     Some(ASTNode::NullNode)
 }
 
+/// splits a keyword argument `name=value` into the name and the value
+fn split_keyword(arg: &ASTNode) -> Option<(&[u8], ASTNode)> {
+    let (first, rest) = match arg {
+        ASTNode::Constant { .. } => (arg, &[][..]),
+        ASTNode::Grouped {
+            typ: GroupType::Loose,
+            elems,
+        } => (elems.first()?, &elems[1..]),
+        _ => return None,
+    };
+    let (name, after) = str_split_at_param_name(first.as_constant()?);
+    let value = after.strip_prefix(b"=").filter(|_| !name.is_empty())?;
+    let mut elems = VAN::with_capacity(1 + rest.len());
+    if !value.is_empty() {
+        elems.push(ASTNode::Constant {
+            non_space: true,
+            data: value.into(),
+        });
+    }
+    elems.extend_from_slice(rest);
+    let value = ASTNode::Grouped {
+        typ: GroupType::Loose,
+        elems,
+    };
+    Some((name, value.simplify()))
+}
+
+/// parses the named parameters of a definition, e.g. `(href text=$href)`
+fn parse_params(elems: &[ASTNode]) -> Option<Argc> {
+    CmdEvalArgs::from_wsdelim(elems.to_vec())
+        .iter()
+        .map(|i| {
+            if let Some((name, default)) = split_keyword(i) {
+                return Some(Param {
+                    name: name.into(),
+                    default: Some(default),
+                });
+            }
+            match str_split_at_param_name(i.as_constant()?) {
+                (name, []) if !name.is_empty() => Some(Param {
+                    name: name.into(),
+                    default: None,
+                }),
+                _ => None,
+            }
+        })
+        .collect::<Option<Vec<_>>>()
+        .map(Argc::named)
+}
+
+/// binds the arguments of a call to the named parameters `params`: keyword arguments
+/// (`name=value`) by name, the other ones by position, omitted arguments are
+/// replaced by the defaults
+pub(crate) fn bind_arguments(
+    params: &[Param],
+    args: &CmdEvalArgs,
+) -> Result<CmdEvalArgs, ArgumentErrorKind> {
+    let mut bound = vec![None; params.len()];
+    let mut next = 0;
+    for i in args.iter() {
+        let keyword = split_keyword(i)
+            .and_then(|(name, value)| Some((params.iter().position(|j| j.name == name)?, value)));
+        let (idx, value) = match keyword {
+            Some(x) => x,
+            None => {
+                next += 1;
+                (next - 1, i.clone())
+            }
+        };
+        match bound.get_mut(idx) {
+            None => return Err(ArgumentErrorKind::TooMany),
+            Some(Some(_)) => return Err(ArgumentErrorKind::Duplicate(params[idx].name.clone())),
+            Some(x) => *x = Some(value),
+        }
+    }
+
+    let mut ret = VAN::with_capacity(params.len());
+    for (param, value) in params.iter().zip(bound) {
+        let value = match (value, &param.default) {
+            (Some(x), _) => x,
+            // the defaults can only refer to the preceding parameters
+            (None, Some(default)) => default
+                .apply_call_arguments(&ret, ret.len(), &params[..ret.len()])
+                .map_err(|idx| {
+                    ArgumentErrorKind::Missing(
+                        params
+                            .get(idx)
+                            .map_or_else(|| idx.to_string().into(), |j| j.name.clone()),
+                    )
+                })?,
+            (None, None) => return Err(ArgumentErrorKind::Missing(param.name.clone())),
+        };
+        ret.push(value);
+    }
+    Ok(CmdEvalArgs(ret))
+}
+
+/// like `def-lazy`, but the results of calls with fully evaluated arguments
+/// are cached, which is only correct if the macro has no side effects
+fn blti_def_memo(args: &mut CmdEvalArgs, ctx: &mut EvalContext<'_>) -> Option<ASTNode> {
+    let ret = blti_def_lazy(args, ctx)?;
+    let varname = args.0[0].conv_to_constant()?.into_owned();
+    ctx.memo.insert(varname, HashMap::new());
+    Some(ret)
+}
+
 fn blti_foreach(args: &mut CmdEvalArgs, ctx: &mut EvalContext<'_>) -> Option<ASTNode> {
     let args = &mut args.0;
     args[0].eval(ctx);
@@ -267,8 +595,7 @@ fn blti_foreach(args: &mut CmdEvalArgs, ctx: &mut EvalContext<'_>) -> Option<AST
                 })
             }
             _ => elems.try_fold(Vec::new(), |mut acc, i| {
-                let mut cur: ASTNode = args[1].clone();
-                cur.apply_arguments_inplace(&i).ok()?;
+                let mut cur = args[1].apply_arguments(&i).ok()?;
                 cur.eval(ctx);
                 acc.push(cur);
                 Some(acc)
@@ -286,35 +613,72 @@ fn blti_fseq(args: &mut CmdEvalArgs, ctx: &mut EvalContext<'_>) -> Option<ASTNod
     }
 }
 
+fn load_ast(path: &Path, ctx: &EvalContext<'_>) -> Result<VAN, anyhow::Error> {
+    let input = ctx
+        .loader
+        .load(path)
+        .with_context(|| format!("unable to read file '{}'", path.display()))?;
+    crate::parser::bytes2ast(path, &input, &ctx.opts)
+}
+
 fn blti_include(args: &mut CmdEvalArgs, ctx: &mut EvalContext<'_>) -> Option<ASTNode> {
     let args = &mut args.0;
     args[0].eval(ctx);
     let filename = args[0].conv_to_constant()?;
     let filename: &str = std::str::from_utf8(&filename).expect("got invalid include filename");
-    Some(
-        {
-            cfg_if! {
-                if #[cfg(feature = "compile")] {
-                    match ctx.comp_map.get(Path::new(filename)).copied() {
-                        None => crate::parser::file2ast(Path::new(filename), ctx.opts),
-                        Some(compf) => ctx.load_from_compfile(&compf),
-                    }
-                } else {
-                    crate::parser::file2ast(Path::new(filename), ctx.opts)
-                }
+    let checked =
+        |ctx: &mut EvalContext<'_>, path: &Path| match ctx.sandbox.check(path, &*ctx.loader) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                ctx.fail(e);
+                None
             }
+        };
+    let filename = Path::new(filename);
+    cfg_if! {
+        if #[cfg(feature = "compile")] {
+            // a mapped compfile is loaded instead of the file itself,
+            // thus only the compfile needs to be accessible
+            let compf = ctx.comp_map.get(filename).copied();
+            let path = checked(ctx, compf.unwrap_or(filename))?;
+            let res = match compf {
+                Some(_) => ctx.load_from_compfile(&path),
+                None => load_ast(&path, ctx),
+            };
+        } else {
+            let path = checked(ctx, filename)?;
+            let res = load_ast(&path, ctx);
         }
-        .expect("expected valid file")
-        .lift_ast(),
-    )
+    }
+    match res {
+        Ok(x) => Some(x.lift_ast()),
+        Err(e) => {
+            ctx.fail(IncludeError {
+                path,
+                reason: format!("{:#}", e),
+            });
+            None
+        }
+    }
+}
+
+/// extracts the message of a caught panic
+pub(crate) fn panic_message(e: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(x) = e.downcast_ref::<&str>() {
+        x.to_string()
+    } else if let Some(x) = e.downcast_ref::<String>() {
+        x.clone()
+    } else {
+        "unknown error".to_string()
+    }
 }
 
 fn blti_lambda(args: &[ASTNode]) -> Option<ASTNode> {
     if args.len() < 2 {
         None
     } else {
-        let largc: usize = atoi(&args[0].conv_to_constant()?).expect("expected number as argc");
-        let body = Box::new(args[1..].to_vec().lift_ast().simplify());
+        let largc = Argc::parse(&args[0].conv_to_constant()?).expect("expected number as argc");
+        let body = Arc::new(args[1..].to_vec().lift_ast().simplify());
         Some(ASTNode::Lambda { argc: largc, body })
     }
 }
@@ -324,8 +688,8 @@ fn blti_lambda_lazy(args: &mut CmdEvalArgs, ctx: &mut EvalContext<'_>) -> Option
         None
     } else {
         let args = &mut args.0;
-        let largc: usize = atoi(&unpack(&mut args[0], ctx)?).expect("expected number as argc");
-        let body = Box::new(args[1..].to_vec().lift_ast().simplify());
+        let largc = Argc::parse(&unpack(&mut args[0], ctx)?).expect("expected number as argc");
+        let body = Arc::new(args[1..].to_vec().lift_ast().simplify());
         Some(ASTNode::Lambda { argc: largc, body })
     }
 }
@@ -336,8 +700,8 @@ fn blti_lambda_strict(args: &mut CmdEvalArgs, ctx: &mut EvalContext<'_>) -> Opti
         None
     } else {
         Some(ASTNode::Lambda {
-            argc: atoi(&args[0].conv_to_constant()?).expect("expected number as argc"),
-            body: Box::new(args[1..].to_vec().lift_ast().simplify()),
+            argc: Argc::parse(&args[0].conv_to_constant()?).expect("expected number as argc"),
+            body: Arc::new(args[1..].to_vec().lift_ast().simplify()),
         })
     }
 }
@@ -351,7 +715,9 @@ fn blti_suppress(_args: &[ASTNode]) -> Option<ASTNode> {
 
 fn blti_undef(args: &mut CmdEvalArgs, ctx: &mut EvalContext<'_>) -> Option<ASTNode> {
     let varname = unpack(&mut args.0[0], ctx)?;
+    ctx.memo.remove(&varname);
     ctx.defs.remove(&varname);
+    ctx.generation += 1;
     Some(ASTNode::NullNode)
 }
 
@@ -401,19 +767,85 @@ fn eval_args(args: &mut CmdEvalArgs, ctx: &mut EvalContext) {
 }
 
 fn eval_cmd(cmd: &mut VAN, args: &mut CmdEvalArgs, ctx: &mut EvalContext) -> Option<ASTNode> {
+    let callee = eval_cmd_name(cmd, ctx)?;
+    let callee = &*callee;
+    let name = match callee {
+        ASTNode::Constant { data, .. } => data.to_vec(),
+        _ => b"lambda".to_vec(),
+    };
+    if !ctx.enter(name) {
+        return None;
+    }
+    if let Some(p) = &mut ctx.profile {
+        p.enter(1 + callee.get_complexity() + args.get_complexity());
+    }
+    let ret = if ctx.tracer.is_some() {
+        eval_cmd_traced(callee, args, ctx)
+    } else {
+        eval_cmd_intern(callee, args, ctx)
+    };
+    if let Some(p) = &mut ctx.profile {
+        p.leave(&ctx.call_stack, ret.as_ref().map(Mangle::get_complexity));
+    }
+    ctx.call_stack.pop();
+    ret
+}
+
+/// like [`eval_cmd_intern`], but reports the step to the tracer
+fn eval_cmd_traced(
+    callee: &ASTNode,
+    args: &mut CmdEvalArgs,
+    ctx: &mut EvalContext,
+) -> Option<ASTNode> {
+    if !ctx.trace(callee, args, None, |t, step| t.enter(step)) {
+        ctx.fail(EvalError::Aborted);
+        return None;
+    }
+    let ret = eval_cmd_intern(callee, args, ctx);
+    ctx.trace(callee, args, ret.as_ref(), |t, step| {
+        t.step(step);
+        true
+    });
+    ret
+}
+
+/// evaluates the command name, returns it if it is callable
+fn eval_cmd_name<'a>(cmd: &'a mut VAN, ctx: &mut EvalContext) -> Option<Cow<'a, ASTNode>> {
     // evaluate command name
     for i in cmd.iter_mut() {
         i.eval(ctx);
     }
     // allow partial evaluation of command name
     *cmd = compact_toplevel(cmd.take());
+    if let [x @ ASTNode::Constant {
+        non_space: true, ..
+    }]
+    | [x @ ASTNode::Lambda { .. }] = &cmd[..]
+    {
+        // avoid cloning lambdas on each call
+        return Some(Cow::Borrowed(x));
+    }
     match cmd.clone().lift_ast().simplify() {
+        x @ ASTNode::Constant {
+            non_space: true, ..
+        }
+        | x @ ASTNode::Lambda { .. } => Some(Cow::Owned(x)),
+        _ => None,
+    }
+}
+
+fn eval_cmd_intern(
+    callee: &ASTNode,
+    args: &mut CmdEvalArgs,
+    ctx: &mut EvalContext,
+) -> Option<ASTNode> {
+    match callee {
         ASTNode::Constant {
             non_space: true,
             data: cmd,
         } => {
             // evaluate command
-            let cmd: &[u8] = &*cmd;
+            let cmd: &[u8] = cmd;
             if let Some((a, x)) = ctx.procdefs.get(cmd).copied() {
                 if let BuiltInFn::Automatic(_) = &x {
                     eval_args(args, ctx);
@@ -426,47 +858,137 @@ fn eval_cmd(cmd: &mut VAN, args: &mut CmdEvalArgs, ctx: &mut EvalContext) -> Opt
                     },
                 }
             } else {
-                let (n, mut x) = ctx.defs.get(cmd)?.clone();
+                let (n, x) = ctx.defs.get(cmd)?.clone();
                 eval_args(args, ctx);
-                if args.len() != n || x.apply_arguments_inplace(args).is_err() {
+                let bound;
+                let args = if n.params.is_some() {
+                    match bind_arguments(n.params(), args) {
+                        Ok(y) => {
+                            bound = y;
+                            &bound
+                        }
+                        // the arguments might change once they are evaluated
+                        Err(_) if !args.iter().all(is_evaluated) => return None,
+                        Err(kind) => {
+                            ctx.fail(ArgumentError {
+                                name: cmd.into(),
+                                kind,
+                            });
+                            return None;
+                        }
+                    }
+                } else {
+                    &*args
+                };
+                if !n.accepts(args.len()) {
                     None
+                } else if ctx.memo.contains_key(cmd) {
+                    eval_memoized(cmd, &n, &x, args, ctx)
                 } else {
-                    Some(x)
+                    x.apply_call_arguments(&args.0, n.fixed, n.params()).ok()
                 }
             }
         }
-        ASTNode::Lambda { argc, mut body } => {
+        ASTNode::Lambda { argc, body } => {
             eval_args(args, ctx);
-            if args.len() != argc || body.apply_arguments_inplace(args).is_err() {
+            if !argc.accepts(args.len()) {
                 None
             } else {
-                Some(*body)
+                body.apply_call_arguments(&args.0, argc.fixed, argc.params())
+                    .ok()
             }
         }
         _ => None,
     }
 }
 
+/// instantiates and evaluates a macro defined with `def-memo`, using its cache
+/// if the arguments are fully evaluated
+fn eval_memoized(
+    name: &[u8],
+    argc: &Argc,
+    body: &ASTNode,
+    args: &CmdEvalArgs,
+    ctx: &mut EvalContext,
+) -> Option<ASTNode> {
+    if ctx.memo_generation != ctx.generation {
+        // the cached results might depend on changed definitions
+        ctx.memo.values_mut().for_each(HashMap::clear);
+        ctx.memo_generation = ctx.generation;
+    }
+    let cacheable = args.iter().all(is_evaluated);
+    if cacheable {
+        if let Some(x) = ctx.memo.get(name).and_then(|i| i.get(&args.0)) {
+            return Some(x.clone());
+        }
+    }
+    let mut ret = body
+        .apply_call_arguments(&args.0, argc.fixed, argc.params())
+        .ok()?;
+    ret.eval(ctx);
+    // incomplete results and results of calls with side effects aren't cached
+    if cacheable
+        && is_evaluated(&ret)
+        && ctx.error.is_none()
+        && ctx.memo_generation == ctx.generation
+    {
+        if let Some(cache) = ctx.memo.get_mut(name) {
+            cache.insert(args.0.clone(), ret.clone());
+        }
+    }
+    Some(ret)
+}
+
 trait Eval {
-    /// if (return value): fully evaluated
+    /// if (return value): fully evaluated, or at least expanded once
+    /// (the expansion is evaluated too, but might get stuck)
     fn eval(&mut self, ctx: &mut EvalContext) -> bool;
 }
 
+/// checks if the node doesn't contain any command evaluations (outside of lambdas)
+pub(crate) fn is_evaluated(node: &ASTNode) -> bool {
+    match node {
+        ASTNode::CmdEval { .. } => false,
+        ASTNode::Grouped { elems, .. } => elems.iter().all(is_evaluated),
+        _ => true,
+    }
+}
+
 impl Eval for ASTNode {
-    fn eval(mut self: &mut Self, ctx: &mut EvalContext) -> bool {
+    fn eval(&mut self, ctx: &mut EvalContext) -> bool {
         use ASTNode::*;
-        match &mut self {
-            CmdEval { cmd, args } => {
-                if let Some(x) = eval_cmd(cmd, args, ctx) {
-                    *self = x;
-                    true
-                } else {
-                    false
-                }
-            }
-            Grouped { elems, .. } => elems.eval(ctx),
-            _ => true,
+        if !matches!(self, CmdEval { .. } | Grouped { .. }) {
+            return true;
+        } else if !ctx.descend() {
+            return false;
         }
+        let mut expanded = false;
+        // the previous states of a chain of expansions into command evaluations
+        let mut seen = HashSet::new();
+        let ret = loop {
+            match self {
+                CmdEval { cmd, args } => match eval_cmd(cmd, args, ctx) {
+                    // the expansion is stuck in a cycle, e.g. `\(a)` with `a` = `\(a)`
+                    Some(x @ CmdEval { .. }) if x == *self || seen.contains(&x) => break expanded,
+                    // evaluate the expansion right away, in place
+                    Some(x) => {
+                        if !ctx.check_output(|| x.get_complexity()) {
+                            break expanded;
+                        }
+                        let prev = std::mem::replace(self, x);
+                        if let CmdEval { .. } = self {
+                            seen.insert(prev);
+                        }
+                        expanded = true;
+                    }
+                    None => break expanded,
+                },
+                Grouped { elems, .. } => break elems.eval(ctx) || expanded,
+                _ => break true,
+            }
+        };
+        ctx.depth -= 1;
+        ret
     }
 }
 
@@ -494,23 +1016,663 @@ impl<'a> EvalContext<'a> {
             procdefs: BUILTINS.clone(),
             opts,
             comp_map,
+            limits: Limits::default(),
+            sandbox: Sandbox::default(),
+            loader: Arc::new(StdFileLoader),
+            tracer: None,
+            profile: None,
+            steps: 0,
+            depth: 0,
+            call_stack: Vec::new(),
+            error: None,
+            location: None,
+            generation: 0,
+            memo: HashMap::new(),
+            memo_generation: 0,
             _non_exhaustive: PhantomData,
         }
     }
 }
 
-pub fn eval(data: &mut VAN, ctx: &mut EvalContext<'_>, _comp_out: Option<&std::path::Path>) {
-    crate::ast::while_cplx_changes(data, |data| {
-        data.eval(ctx);
-        *data = compact_toplevel(data.take());
-        true
-    });
+impl<'a> EvalContext<'a> {
+    /// creates a context with the same definitions and settings, but without
+    /// tracer and evaluation state, e.g. to evaluate another input on another
+    /// thread; the definition bodies are shared, and the profile (if any) starts empty
+    pub fn fork(&self) -> Self {
+        let mut ret = Self::new(self.opts.clone(), self.comp_map.clone());
+        ret.defs = self.defs.clone();
+        ret.procdefs = self.procdefs.clone();
+        ret.limits = self.limits;
+        ret.sandbox = self.sandbox.clone();
+        ret.loader = self.loader.clone();
+        ret.profile = self.profile.as_ref().map(|_| Profile::new());
+        ret.memo = self
+            .memo
+            .keys()
+            .map(|i| (i.clone(), HashMap::new()))
+            .collect();
+        ret
+    }
+}
+
+impl EvalContext<'_> {
+    /// records an error, which stops the evaluation
+    fn fail(&mut self, e: impl Into<EvalError>) {
+        if self.error.is_none() {
+            self.error = Some(e.into());
+        }
+    }
+
+    /// records that the given limit was exceeded
+    fn exceeded(&mut self, kind: LimitKind) {
+        self.fail(LimitExceeded {
+            kind,
+            chain: self.call_stack.clone(),
+        });
+    }
+
+    /// registers the evaluation of the command `name`,
+    /// returns `false` if the evaluation should be stopped
+    fn enter(&mut self, name: Vec<u8>) -> bool {
+        if self.error.is_some() {
+            return false;
+        }
+        self.steps += 1;
+        self.call_stack.push(name);
+        match self.limits.max_steps {
+            Some(x) if self.steps > x => {
+                self.exceeded(LimitKind::Steps(x));
+                self.call_stack.pop();
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// registers a nested evaluation,
+    /// returns `false` if the evaluation should be stopped
+    fn descend(&mut self) -> bool {
+        if self.error.is_some() {
+            return false;
+        }
+        match self.limits.max_depth {
+            Some(x) if self.depth >= x => {
+                self.exceeded(LimitKind::Depth(x));
+                false
+            }
+            _ => {
+                self.depth += 1;
+                true
+            }
+        }
+    }
+
+    /// passes the currently evaluated step to the tracer
+    fn trace(
+        &mut self,
+        callee: &ASTNode,
+        args: &CmdEvalArgs,
+        result: Option<&ASTNode>,
+        f: impl FnOnce(&mut dyn Tracer, &TraceStep<'_>) -> bool,
+    ) -> bool {
+        let mut tracer = match self.tracer.take() {
+            Some(x) => x,
+            None => return true,
+        };
+        let step = TraceStep {
+            name: self.call_stack.last().map_or(&[][..], |x| &x[..]),
+            callee,
+            args,
+            result,
+            depth: self.call_stack.len().saturating_sub(1),
+            location: self.location,
+        };
+        let ret = f(&mut *tracer, &step);
+        self.tracer = Some(tracer);
+        ret
+    }
+
+    /// checks the complexity of the top-level output (only computed if limited),
+    /// returns `false` if the evaluation should be stopped
+    pub(crate) fn check_output(&mut self, complexity: impl FnOnce() -> usize) -> bool {
+        match self.limits.max_output {
+            Some(x) if complexity() > x => self.exceeded(LimitKind::Output(x)),
+            _ => {}
+        }
+        self.error.is_none()
+    }
+
+    /// returns the error which stopped the evaluation, if any, and resets the
+    /// evaluation state to allow further evaluations with this context
+    pub(crate) fn take_error(&mut self) -> Result<(), EvalError> {
+        self.call_stack.clear();
+        self.depth = 0;
+        self.error.take().map_or(Ok(()), Err)
+    }
+}
+
+/// evaluates a single node, without the top-level loop of [`eval`]
+///
+/// # Return value
+/// * `Ok(true)` if the node was fully evaluated
+pub fn eval_node(node: &mut ASTNode, ctx: &mut EvalContext<'_>) -> Result<bool, EvalError> {
+    node.eval(ctx);
+    ctx.take_error()?;
+    Ok(is_evaluated(node))
+}
+
+/// drives the evaluation of the top-level nodes: each node is evaluated to
+/// completion in a single traversal, and the nodes which are still pending
+/// afterwards (e.g. because they reference macros which are defined later)
+/// are retried as long as the definitions change
+///
+/// `eval_one` returns `true` if the node was fully evaluated,
+/// `complexity` is used to check the output limit, and `flush` is called
+/// (in order) with each node as soon as it and all nodes before it are
+/// fully evaluated, thus final
+pub(crate) fn eval_toplevel<T>(
+    nodes: &mut [T],
+    ctx: &mut EvalContext<'_>,
+    mut eval_one: impl FnMut(&mut T, &mut EvalContext<'_>) -> bool,
+    complexity: impl Fn(&[T]) -> usize,
+    mut flush: impl FnMut(&mut T, &mut EvalContext<'_>),
+) {
+    let mut pending: Vec<_> = (0..nodes.len()).collect();
+    let mut flushed = 0;
+    loop {
+        let generation = ctx.generation;
+        let mut still_pending = Vec::new();
+        for i in pending {
+            if !eval_one(&mut nodes[i], ctx) {
+                still_pending.push(i);
+            } else if still_pending.is_empty() && ctx.error.is_none() {
+                for j in &mut nodes[flushed..=i] {
+                    flush(j, ctx);
+                }
+                flushed = i + 1;
+            }
+        }
+        pending = still_pending;
+        if !ctx.check_output(|| complexity(nodes))
+            || pending.is_empty()
+            || generation == ctx.generation
+        {
+            break;
+        }
+    }
+}
+
+pub fn eval(
+    data: &mut VAN,
+    ctx: &mut EvalContext<'_>,
+    comp_out: Option<&std::path::Path>,
+) -> Result<(), EvalError> {
+    eval_toplevel(
+        data,
+        ctx,
+        |i, ctx| {
+            i.eval(ctx);
+            is_evaluated(i)
+        },
+        |x| x.iter().map(Mangle::get_complexity).sum(),
+        |_, _| {},
+    );
+    ctx.take_error()?;
+    *data = compact_toplevel(data.take());
+    finish(data, ctx, comp_out);
+    Ok(())
+}
+
+/// like [`eval`], but writes the result into `out`: each top-level node is
+/// written as soon as it and all nodes before it are fully evaluated,
+/// which keeps the memory usage bounded for large outputs
+///
+/// In case of an error, the output written so far is incomplete.
+pub fn eval_streaming<W: io::Write + ?Sized>(
+    mut data: VAN,
+    ctx: &mut EvalContext<'_>,
+    out: &mut W,
+) -> Result<(), EvalError> {
+    // the complexity of the nodes which were already written
+    let written = Cell::new(0);
+    let mut write = |node: &mut ASTNode, ctx: &mut EvalContext<'_>| {
+        written.set(written.get() + node.get_complexity());
+        let res = compact_toplevel(node.take().lift_ast()).write_to(out, &ctx.opts);
+        if let Err(e) = res {
+            ctx.fail(EvalError::Output(e.kind()));
+        }
+    };
+    eval_toplevel(
+        &mut data,
+        ctx,
+        |i, ctx| {
+            i.eval(ctx);
+            is_evaluated(i)
+        },
+        |x| written.get() + x.iter().map(Mangle::get_complexity).sum::<usize>(),
+        &mut write,
+    );
+    ctx.take_error()?;
+    // the remaining nodes couldn't be evaluated completely
+    for i in data.iter_mut() {
+        write(i, ctx);
+    }
+    ctx.take_error()?;
+    out.flush().map_err(|e| EvalError::Output(e.kind()))
+}
+
+/// like [`eval`], but tracks from which top-level node (given by its byte offset
+/// in the input) each step originates, see [`TraceStep::location`]; nodes produced
+/// by a top-level node, e.g. the content of an included file, inherit its location
+pub fn eval_spanned(
+    mut nodes: Vec<(usize, ASTNode)>,
+    ctx: &mut EvalContext<'_>,
+    comp_out: Option<&std::path::Path>,
+) -> Result<VAN, EvalError> {
+    eval_toplevel(
+        &mut nodes,
+        ctx,
+        |(location, node), ctx| {
+            ctx.location = Some(*location);
+            node.eval(ctx);
+            is_evaluated(node)
+        },
+        |x| x.iter().map(|i| i.1.get_complexity()).sum(),
+        |_, _| {},
+    );
+    ctx.location = None;
+    ctx.take_error()?;
+    let data = compact_toplevel(nodes.into_iter().map(|i| i.1).collect());
+    finish(&data, ctx, comp_out);
+    Ok(data)
+}
+
+/// writes the compfile, if requested
+fn finish(data: &VAN, ctx: &EvalContext<'_>, comp_out: Option<&std::path::Path>) {
     cfg_if! {
         if #[cfg(feature = "compile")] {
-            if let Some(comp_out) = _comp_out {
-                ctx.save_to_compfile(comp_out, &*data)
+            if let Some(comp_out) = comp_out {
+                ctx.save_to_compfile(comp_out, data)
                     .expect("save failed");
             }
+        } else {
+            let _ = (data, ctx, comp_out);
+        }
+    }
+}
+
+/// evaluates `input` in a new context, which is passed to `setup` beforehand
+///
+/// # Return value
+/// * the formatted output and the context after the evaluation
+#[cfg(test)]
+pub(crate) fn eval_with(
+    input: &str,
+    setup: impl FnOnce(&mut EvalContext<'static>),
+) -> Result<(Vec<u8>, EvalContext<'static>), EvalError> {
+    let opts = ParserOptions::default();
+    let mut data = crate::parser::parse_toplevel(input.as_bytes(), &opts).unwrap();
+    let mut ctx = EvalContext::new(opts, HashMap::new());
+    setup(&mut ctx);
+    eval(&mut data, &mut ctx, None)?;
+    let mut ret = Vec::new();
+    data.fmt(&mut ret, &ctx.opts);
+    Ok((ret, ctx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval_str(input: &str, limits: Limits) -> Result<(), LimitExceeded> {
+        match eval_with(input, |ctx| ctx.limits = limits) {
+            Ok(_) => Ok(()),
+            Err(EvalError::LimitExceeded(e)) => Err(e),
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_eval_order() {
+        let eval = |input| eval_with(input, |_| {}).unwrap().0;
+        // expansions are evaluated right away
+        assert_eq!(
+            eval("\\def(g 0 A)\\def-lazy(f 0 \\(g))\\(f)\\def(g 0 B)\\(f)"),
+            b"AB"
+        );
+        // pending evaluations are retried after the definitions changed
+        assert_eq!(eval("\\(a)\\def-lazy(a 0 \\(b))\\def(b 0 x)"), b"x");
+        // cycles are left as-is
+        assert_eq!(
+            eval("\\def-lazy(a 0 \\(b))\\def-lazy(b 0 \\(a))\\(a)"),
+            b"\\(a)"
+        );
+    }
+
+    #[test]
+    fn test_limit_steps() {
+        let limits = Limits {
+            max_steps: Some(2),
+            ..Limits::default()
+        };
+        let e = eval_str("\\def-lazy(a 0 \\(a)x)\\(pass \\(a))", limits).unwrap_err();
+        assert_eq!(e.kind, LimitKind::Steps(2));
+        assert_eq!(e.chain, vec![b"pass".to_vec(), b"a".to_vec()]);
+        assert_eq!(
+            e.to_string(),
+            "evaluation step limit (2) exceeded in macro chain: pass -> a"
+        );
+
+        // infinite expansion, without growing nesting
+        let limits = Limits {
+            max_steps: Some(1000),
+            ..Limits::default()
+        };
+        let e = eval_str("\\def-lazy(a 1 \\(a \\(add $0 1)))\\(a 0)", limits).unwrap_err();
+        assert_eq!(e.kind, LimitKind::Steps(1000));
+        assert_eq!(e.chain, vec![b"a".to_vec()]);
+        assert!(eval_str("\\def(a 0 x)\\(a)", limits).is_ok());
+    }
+
+    #[test]
+    fn test_limit_depth() {
+        // the nesting grows with each expansion, which would overflow the stack
+        let e = eval_str("\\def-lazy(a 0 (\\(a)))\\(a)", Limits::default()).unwrap_err();
+        assert_eq!(e.kind, LimitKind::Depth(256));
+        assert_eq!(e.to_string(), "recursion depth limit (256) exceeded");
+
+        let limits = Limits {
+            max_depth: Some(2),
+            ..Limits::default()
+        };
+        let e = eval_str("\\(pass \\(pass \\(pass x)))", limits).unwrap_err();
+        assert_eq!(e.chain, vec![b"pass".to_vec(), b"pass".to_vec()]);
+    }
+
+    #[test]
+    fn test_limit_output() {
+        let limits = Limits {
+            max_output: Some(1000),
+            ..Limits::default()
+        };
+        // the size of each expansion is checked, too
+        let e = eval_str("\\def-lazy(a 1 \\(a $0$0))\\(a x)", limits).unwrap_err();
+        assert_eq!(e.kind, LimitKind::Output(1000));
+        assert!(e.chain.is_empty());
+
+        let mut input = String::from("\\def-lazy(l0 0 x)");
+        for i in 1..10 {
+            input += &format!("\\def-lazy(l{} 0 {{\\(l{1})\\(l{1})}})", i, i - 1);
+        }
+        input += "\\(l9)";
+        let e = eval_str(&input, limits).unwrap_err();
+        assert_eq!(e.kind, LimitKind::Output(1000));
+    }
+
+    #[test]
+    fn test_variadic() {
+        let run = |input| eval_with(input, |_| {}).unwrap().0;
+        assert_eq!(
+            run("\\def-lazy(f 1+ $0:$#:\\(pass $@))\\(f a)\\(f a b c)"),
+            b"a:1:a:3:bc"
+        );
+        // too few arguments
+        assert_eq!(run("\\def-lazy(f 1+ $0)\\(f)"), b"\\(f)");
+        // fseq-like wrappers
+        assert_eq!(
+            run("\\def-lazy(list 0+ \\foreach({$*} {<li>$$0</li>}))\\(list a b \\(add 1 2))"),
+            b"<li>a</li><li>b</li><li>3</li>"
+        );
+        assert_eq!(
+            run("\\def-lazy(seq 0+ $@)\\(seq \\def(x 0 y) \\(x) z)"),
+            b"yz"
+        );
+        // lambdas, which can't be curried
+        assert_eq!(run("\\(\\lambda(1+ $0$#) a b)"), b"a2");
+        assert_eq!(
+            run("\\(\\curry(\\lambda(1+ $0) a))"),
+            b"\\(\\(curry \\(lambda 1+ $0) a))"
+        );
+        // the arguments of non-variadic definitions are all positional
+        assert_eq!(run("\\def-lazy(g 2 [$#$@$*])\\(g a b)"), b"[2]");
+    }
+
+    #[test]
+    fn test_named_params() {
+        let run = |input: &str| eval_with(input, |_| {}).unwrap().0;
+        let link = "\\def(link (href text=$href) {<a href=\"$href\">$text</a>})";
+        assert_eq!(
+            run(&format!("{}\\(link x)|\\(link x y)", link)),
+            br#"<a href="x">x</a>|<a href="x">y</a>"#
+        );
+        // keyword arguments can be given in any order
+        assert_eq!(
+            run(&format!("{}\\(link text={{a b}} href=\\(add 1 2))", link)),
+            br#"<a href="3">a b</a>"#
+        );
+        // unknown names are kept, like positional arguments
+        assert_eq!(
+            run("\\def(f (a b=) [$a|$b|$c])\\(f x)\\(f c=1)\\(f b=1 x)"),
+            b"[x||$c][c=1||$c][x|1|$c]"
+        );
+        // nested bodies refer to their own arguments with `$$0`
+        assert_eq!(
+            run("\\def-lazy(g (sep) \\foreach((a b) {$$0$sep}))\\(g ,)"),
+            b"a,b,"
+        );
+
+        let err = |input| match eval_with(input, |_| {}).map(|(out, _)| out) {
+            Err(EvalError::InvalidArguments(e)) => e.to_string(),
+            x => panic!("expected argument error, got {:?}", x),
+        };
+        assert_eq!(
+            err("\\def(f (a b) $a$b)\\(f x)"),
+            "invalid call of 'f': missing argument 'b'"
+        );
+        assert_eq!(
+            err("\\def(f (a b) $a$b)\\(f x a=y)"),
+            "invalid call of 'f': argument 'a' given more than once"
+        );
+        assert_eq!(
+            err("\\def(f (a) $a)\\(f x y)"),
+            "invalid call of 'f': too many arguments"
+        );
+    }
+
+    #[test]
+    fn test_def_memo() {
+        let run = |input| eval_with(input, |_| {}).unwrap().0;
+        assert_eq!(
+            run("\\def-memo(f 1 <$0>)\\(f a)\\(f b)\\(f a)"),
+            b"<a><b><a>"
+        );
+        // the cached results are dropped when the definitions change
+        assert_eq!(
+            run("\\def-memo(f 1 $0\\(g))\\def(g 0 A)\\(f x)\\def(g 0 B)\\(f x)"),
+            b"xAxB"
+        );
+        // calls with side effects are evaluated each time
+        assert_eq!(
+            run("\\def-memo(f 1 \\def(y 0 $0)\\(y))\\(f a)\\(f b)\\(f a)\\(y)"),
+            b"abaa"
+        );
+
+        // the body is only evaluated once per distinct argument
+        let (out, ctx) = eval_with(
+            "\\def-memo(f 1 \\(add $0 1))\\(f 1)\\(f 2)\\(f 1)\\(f 1)",
+            |ctx| ctx.profile = Some(Profile::new()),
+        )
+        .unwrap();
+        assert_eq!(out, b"2322");
+        let prof = ctx.profile.unwrap();
+        assert_eq!(prof.macros[&b"f"[..]].calls, 4);
+        assert_eq!(prof.macros[&b"add"[..]].calls, 2);
+    }
+
+    #[test]
+    fn test_eval_streaming() {
+        let stream = |input: &str, out: &mut Vec<u8>| {
+            let opts = ParserOptions::default();
+            let data = crate::parser::parse_toplevel(input.as_bytes(), &opts).unwrap();
+            let mut ctx = EvalContext::new(opts, HashMap::new());
+            eval_streaming(data, &mut ctx, out)
+        };
+        for input in &[
+            "A\\(b)C\\def(b 0 B)",
+            "\\def(g 0 A)\\def-lazy(f 0 \\(g))\\(f) \\def(g 0 B)\\(f)",
+            "\\def-lazy(a 0 \\(b))\\def-lazy(b 0 \\(a))x\\(a)y",
+        ] {
+            let mut out = Vec::new();
+            stream(input, &mut out).unwrap();
+            assert_eq!(out, eval_with(input, |_| {}).unwrap().0, "{}", input);
+        }
+
+        // the final prefix is written before the error occurs
+        let mut out = Vec::new();
+        match stream("A\\def-lazy(a 0 (\\(a)))B\\(a)C", &mut out) {
+            Err(EvalError::LimitExceeded(e)) => assert_eq!(e.kind, LimitKind::Depth(256)),
+            x => panic!("expected limit error, got {:?}", x),
+        }
+        assert_eq!(out, b"AB");
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let dir = std::env::temp_dir().join(format!("crulz-sandbox-test-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("inner.crulz"), "inner").unwrap();
+        std::fs::write(dir.join("secret.crulz"), "secret").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.join("secret.crulz"), root.join("link.crulz")).unwrap();
+
+        let include = |path: &Path, sandbox: &Sandbox| {
+            eval_with(&format!("\\include({})", path.display()), |ctx| {
+                ctx.sandbox = sandbox.clone()
+            })
+            .map(|(out, _)| out)
+        };
+        let denied = |path: &Path, sandbox: &Sandbox| match include(path, sandbox) {
+            Err(EvalError::SandboxViolation(e)) => e.reason,
+            x => panic!(
+                "expected sandbox violation for {}, got {:?}",
+                path.display(),
+                x
+            ),
+        };
+
+        let inner = root.join("inner.crulz");
+        assert_eq!(include(&inner, &Sandbox::Disabled).unwrap(), b"inner");
+        assert_eq!(
+            denied(&inner, &Sandbox::DenyAll),
+            "file system access is disabled"
+        );
+
+        let sandbox = Sandbox::AllowRoots(vec![root.clone()]);
+        assert_eq!(include(&inner, &sandbox).unwrap(), b"inner");
+        let outside = "path is outside of the allowed roots";
+        assert_eq!(denied(&root.join("../secret.crulz"), &sandbox), outside);
+        assert_eq!(denied(Path::new("/etc/passwd"), &sandbox), outside);
+        #[cfg(unix)]
+        assert_eq!(denied(&root.join("link.crulz"), &sandbox), outside);
+        assert!(denied(&root.join("missing.crulz"), &sandbox).starts_with("unable to resolve path"));
+
+        // the evaluation stops at the first violation
+        let e = eval_with(
+            &format!("\\include(/etc/passwd)\\include({})", inner.display()),
+            |ctx| ctx.sandbox = sandbox.clone(),
+        )
+        .map(|(out, _)| out)
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "access to '/etc/passwd' denied by sandbox: path is outside of the allowed roots"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "compile")]
+    #[test]
+    fn test_sandbox_compfile() {
+        use crate::loader::MemoryFileLoader;
+
+        let compfile = |input: &str| {
+            let content = crate::parser::parse_toplevel(input.as_bytes(), &Default::default());
+            let mut z =
+                flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            bincode::serialize_into(&mut z, &content.unwrap()).unwrap();
+            bincode::serialize_into(&mut z, &DefinesMap::new()).unwrap();
+            z.finish().unwrap()
+        };
+        let mut loader = MemoryFileLoader::new();
+        loader.insert("/t/a.crulzc", compfile("compiled"));
+        loader.insert("/secret.crulzc", compfile("secret"));
+        let loader = Arc::new(loader);
+
+        // the mapped source files don't exist
+        let include = |input: &str| {
+            let loader = loader.clone();
+            eval_with(input, move |ctx| {
+                ctx.loader = loader;
+                ctx.sandbox = Sandbox::AllowRoots(vec![PathBuf::from("/t")]);
+                ctx.comp_map
+                    .insert(Path::new("/src/a.crulz"), Path::new("/t/a.crulzc"));
+                ctx.comp_map
+                    .insert(Path::new("/t/b.crulz"), Path::new("/secret.crulzc"));
+            })
+            .map(|(out, _)| out)
+        };
+        assert_eq!(include("\\include(/src/a.crulz)").unwrap(), b"compiled");
+        match include("\\include(/t/b.crulz)") {
+            Err(EvalError::SandboxViolation(e)) => {
+                assert_eq!(e.path, Path::new("/secret.crulzc"));
+                assert_eq!(e.reason, "path is outside of the allowed roots");
+            }
+            x => panic!("expected sandbox violation, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_memory_loader() {
+        use crate::loader::MemoryFileLoader;
+
+        let mut loader = MemoryFileLoader::new();
+        loader.insert("/t/main.crulz", "\\include(/t/sub/defs.crulz)\\(greet x)");
+        loader.insert(
+            "/t/sub/defs.crulz",
+            "\\suppress(\\def-lazy(greet 1 {Hello, $0!}))",
+        );
+        loader.insert("/secret.crulz", "secret");
+        let loader = Arc::new(loader);
+
+        let setup = |sandbox: Sandbox| {
+            let loader = loader.clone();
+            move |ctx: &mut EvalContext<'_>| {
+                ctx.loader = loader;
+                ctx.sandbox = sandbox;
+            }
+        };
+        assert_eq!(
+            eval_with("\\include(/t/main.crulz)", setup(Sandbox::Disabled))
+                .unwrap()
+                .0,
+            b"Hello, x!"
+        );
+
+        let sandbox = Sandbox::AllowRoots(vec![PathBuf::from("/t")]);
+        assert_eq!(
+            eval_with("\\include(/t/main.crulz)", setup(sandbox.clone()))
+                .unwrap()
+                .0,
+            b"Hello, x!"
+        );
+        match eval_with("\\include(/t/sub/../../secret.crulz)", setup(sandbox)).map(|(out, _)| out)
+        {
+            Err(EvalError::SandboxViolation(e)) => {
+                assert_eq!(e.reason, "path is outside of the allowed roots")
+            }
+            x => panic!("expected sandbox violation, got {:?}", x),
         }
     }
 }
@@ -1,5 +1,13 @@
 #![forbid(unsafe_code)]
 
 pub mod ast;
+pub mod batch;
+pub mod formatter;
+pub mod highlight;
 pub mod interp;
+pub mod loader;
+pub mod lsp;
+pub mod optimize;
 pub mod parser;
+pub mod profile;
+pub mod trace;
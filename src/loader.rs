@@ -0,0 +1,135 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+/// abstraction of the file system, used by the interpreter to read
+/// included files and compfiles, and to write compfiles
+pub trait FileLoader: Send + Sync {
+    /// reads the whole file
+    fn load(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// creates or replaces the file with `data`
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()>;
+
+    /// returns the canonical form of the path (with all intermediate
+    /// components normalized and symlinks resolved), which must exist
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+}
+
+/// the real file system
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdFileLoader;
+
+impl FileLoader for StdFileLoader {
+    #[inline]
+    fn load(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    #[inline]
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        std::fs::write(path, data)
+    }
+
+    #[inline]
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+}
+
+/// an in-memory file system, without symlinks;
+/// directories exist implicitly if they contain files,
+/// the files can only be changed via [`MemoryFileLoader::insert`]
+#[derive(Clone, Debug, Default)]
+pub struct MemoryFileLoader {
+    files: HashMap<PathBuf, Vec<u8>>,
+}
+
+/// normalizes `.` and `..` components
+fn normalize(path: &Path) -> PathBuf {
+    let mut ret = PathBuf::new();
+    for i in path.components() {
+        match i {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !ret.pop() {
+                    ret.push(i);
+                }
+            }
+            _ => ret.push(i),
+        }
+    }
+    ret
+}
+
+impl MemoryFileLoader {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// adds or replaces a file
+    pub fn insert(&mut self, path: impl AsRef<Path>, content: impl Into<Vec<u8>>) {
+        self.files.insert(normalize(path.as_ref()), content.into());
+    }
+}
+
+impl FileLoader for MemoryFileLoader {
+    fn load(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.files
+            .get(&normalize(path))
+            .cloned()
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    fn write(&self, _path: &Path, _data: &[u8]) -> io::Result<()> {
+        Err(io::ErrorKind::PermissionDenied.into())
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let path = normalize(path);
+        if self.files.keys().any(|i| i.starts_with(&path)) {
+            Ok(path)
+        } else {
+            Err(io::ErrorKind::NotFound.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_loader() {
+        let mut loader = MemoryFileLoader::new();
+        loader.insert("a/b.crulz", "b");
+        loader.insert("./c.crulz", "c");
+
+        assert_eq!(loader.load(Path::new("a/b.crulz")).unwrap(), b"b");
+        assert_eq!(loader.load(Path::new("a/../a/./b.crulz")).unwrap(), b"b");
+        assert_eq!(loader.load(Path::new("c.crulz")).unwrap(), b"c");
+        assert_eq!(
+            loader.write(Path::new("c.crulz"), b"x").unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            loader.load(Path::new("a")).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        assert_eq!(
+            loader.canonicalize(Path::new("a/./x/..")).unwrap(),
+            Path::new("a")
+        );
+        assert_eq!(
+            loader
+                .canonicalize(Path::new("a/../../c.crulz"))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
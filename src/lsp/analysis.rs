@@ -0,0 +1,247 @@
+use crate::{
+    ast::{Lift as _, Mangle, Node as ASTNode},
+    interp::{eval_node, eval_toplevel, panic_message, DefinesMap, EvalContext, Limits, Sandbox},
+    loader::FileLoader,
+    parser::{incremental::Document, skip_comment, Options as ParserOptions, Parse},
+};
+use bstr::ByteSlice;
+use std::{collections::HashMap, ops::Range, sync::Arc};
+
+// === symbols
+//
+// The AST doesn't contain any source locations, thus the symbols are
+// collected by parsing the node at each occurence of the escape sequence,
+// which also finds nodes nested inside of other nodes and inside of
+// erroneous code.
+
+/// a command evaluation with a constant command name, e.g. `\name(...)`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Call {
+    pub name: Vec<u8>,
+    pub name_span: Range<usize>,
+}
+
+/// a `def`, `def-lazy` or `def-memo` statement
+#[derive(Clone, Debug, PartialEq)]
+pub struct Def {
+    pub name: Vec<u8>,
+    pub span: Range<usize>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    pub calls: Vec<Call>,
+    pub defs: Vec<Def>,
+    /// file names of all `include` statements with a constant argument
+    pub includes: Vec<Vec<u8>>,
+}
+
+impl Symbols {
+    pub fn scan(source: &[u8], opts: &ParserOptions) -> Self {
+        let mut ret = Self::default();
+        let mut pos = 0;
+        while let Some(x) = source[pos..].find(&opts.escc) {
+            let start = pos + x;
+            let data = &source[start..];
+            match skip_comment(data, opts) {
+                Ok(Some(rest)) => {
+                    pos = source.len() - rest.len();
+                    continue;
+                }
+                // unterminated block comment
+                Err(_) => break,
+                Ok(None) => {}
+            }
+            pos = match ASTNode::parse(data, opts) {
+                Ok((rest, ASTNode::CmdEval { cmd, args })) => {
+                    ret.add_cmdeval(
+                        source,
+                        start..(source.len() - rest.len()),
+                        &cmd,
+                        &args.0,
+                        opts,
+                    );
+                    // look into the arguments
+                    start + opts.escc.len()
+                }
+                // escaped constants and verbatim blocks
+                Ok((rest, _)) => source.len() - rest.len(),
+                Err(_) => start + opts.escc.len(),
+            };
+        }
+        ret
+    }
+
+    fn add_cmdeval(
+        &mut self,
+        source: &[u8],
+        span: Range<usize>,
+        cmd: &[ASTNode],
+        args: &[ASTNode],
+        opts: &ParserOptions,
+    ) {
+        let name = match cmd {
+            [x] => match x.as_constant() {
+                Some(x) => x,
+                None => return,
+            },
+            _ => return,
+        };
+        let mut name_start = span.start + opts.escc.len();
+        if source[name_start] == opts.strict_markers.begin {
+            name_start += 1;
+        }
+        let name_span = name_start..(name_start + name.len());
+        if source.get(name_span.clone()) != Some(name) {
+            return;
+        }
+
+        let arg0 = args.first().and_then(ASTNode::conv_to_constant);
+        match (name, arg0) {
+            (b"def", Some(x)) | (b"def-lazy", Some(x)) | (b"def-memo", Some(x))
+                if args.len() >= 2 =>
+            {
+                self.defs.push(Def {
+                    name: x.into_owned(),
+                    span,
+                })
+            }
+            (b"include", Some(x)) if args.len() == 1 => self.includes.push(x.into_owned()),
+            _ => {}
+        }
+        self.calls.push(Call {
+            name: name.to_vec(),
+            name_span,
+        });
+    }
+
+    /// the innermost call whose name contains the given offset
+    pub fn call_at(&self, offset: usize) -> Option<&Call> {
+        self.calls
+            .iter()
+            .filter(|i| i.name_span.start <= offset && offset <= i.name_span.end)
+            .min_by_key(|i| i.name_span.len())
+    }
+}
+
+// === evaluation
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error = 1,
+    Warning = 2,
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub span: Range<usize>,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// the results of evaluating a document
+#[derive(Clone, Debug, Default)]
+pub struct Evaluation {
+    pub defs: DefinesMap,
+    /// built-in functions and their argc, if fixed
+    pub procdefs: HashMap<Vec<u8>, Option<usize>>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// finds the first command evaluation which is left over after evaluation
+fn find_unevaluated(node: &ASTNode) -> Option<&ASTNode> {
+    match node {
+        ASTNode::CmdEval { .. } => Some(node),
+        ASTNode::Grouped { elems, .. } => elems.iter().find_map(find_unevaluated),
+        _ => None,
+    }
+}
+
+impl Evaluation {
+    /// evaluates the top-level nodes of the document like [`interp::eval`](crate::interp::eval),
+    /// but keeps them separate, to be able to map errors back to the source
+    pub fn run(doc: &Document, sandbox: &Sandbox, loader: &Arc<dyn FileLoader>) -> Self {
+        let opts = doc.opts();
+        let mut ctx = EvalContext::new(opts.clone(), HashMap::new());
+        ctx.sandbox = sandbox.clone();
+        ctx.loader = loader.clone();
+        // documents are evaluated after each change, thus runaway
+        // evaluations need to be stopped early
+        ctx.limits = Limits {
+            max_steps: Some(10_000),
+            max_output: Some(1 << 16),
+            ..Limits::default()
+        };
+        let mut nodes: Vec<_> = doc
+            .segments()
+            .iter()
+            .filter_map(|i| match &i.result {
+                Ok(Some(x @ ASTNode::CmdEval { .. })) => Some((i.span.clone(), x.clone(), None)),
+                _ => None,
+            })
+            .collect();
+
+        eval_toplevel(
+            &mut nodes,
+            &mut ctx,
+            |(_, node, failure), ctx| {
+                if failure.is_some() {
+                    return true;
+                }
+                let res =
+                    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| eval_node(node, ctx)));
+                match res {
+                    Ok(Ok(x)) => return x,
+                    Ok(Err(e)) => *failure = Some(e.to_string()),
+                    Err(e) => {
+                        // reset the evaluation state
+                        ctx.take_error().ok();
+                        *failure = Some(panic_message(e));
+                    }
+                }
+                true
+            },
+            |nodes| nodes.iter().map(|i| i.1.get_complexity()).sum(),
+            |_, _| {},
+        );
+        if let Err(e) = ctx.take_error() {
+            // the output limit was exceeded, blame the largest node
+            if let Some(x) = nodes.iter_mut().max_by_key(|i| i.1.get_complexity()) {
+                x.2 = Some(e.to_string());
+            }
+        }
+
+        let diagnostics = nodes
+            .into_iter()
+            .filter_map(|(span, node, failure)| {
+                let (severity, message) = if let Some(x) = failure {
+                    (Severity::Error, format!("evaluation failed: {}", x))
+                } else if let Some(ASTNode::CmdEval { cmd, .. }) = find_unevaluated(&node) {
+                    let mut name = opts.escc.clone();
+                    cmd.clone().lift_ast().fmt(&mut name, opts);
+                    (
+                        Severity::Warning,
+                        format!("unable to evaluate '{}'", name.to_str_lossy()),
+                    )
+                } else {
+                    return None;
+                };
+                Some(Diagnostic {
+                    span,
+                    severity,
+                    message,
+                })
+            })
+            .collect();
+
+        Self {
+            defs: ctx.defs,
+            procdefs: ctx
+                .procdefs
+                .into_iter()
+                .map(|(name, (argc, _))| (name, argc))
+                .collect(),
+            diagnostics,
+        }
+    }
+}
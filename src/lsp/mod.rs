@@ -0,0 +1,708 @@
+//! a language server for crulz templates, used by the `crulz-lsp` binary
+//!
+//! The server speaks the Language Server Protocol (JSON-RPC messages with
+//! `Content-Length` headers) over arbitrary streams, usually stdin and stdout,
+//! and offers diagnostics, go-to-definition, hover and completion.
+//!
+//! Documents which don't use the default syntax need the parser options as
+//! `initializationOptions` (`escc`, `passEscc`, `strictMarkers`, `looseMarkers`
+//! and `argSigil`), which correspond to the command line options of `crulz`.
+
+mod analysis;
+mod tests;
+
+use crate::{
+    ast::Mangle,
+    interp::Sandbox,
+    loader::{FileLoader, StdFileLoader},
+    parser::{incremental::Document, Options as ParserOptions, ScopeMarkers},
+};
+use analysis::{Evaluation, Symbols};
+use bstr::ByteSlice;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+// === protocol
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// the maximum accepted size of a message body, larger messages are rejected
+/// before any memory is allocated for them
+const MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+fn invalid_params(e: serde_json::Error) -> RpcError {
+    RpcError {
+        code: INVALID_PARAMS,
+        message: e.to_string(),
+    }
+}
+
+/// reads a single message, returns `Ok(None)` at EOF
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut len = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            if key.eq_ignore_ascii_case("content-length") {
+                len = Some(value.trim().parse::<usize>().map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("Content-Length: {}", e))
+                })?);
+            }
+        }
+    }
+    let len = len.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header")
+    })?;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Content-Length {} exceeds the maximum message size ({})",
+                len, MAX_MESSAGE_SIZE
+            ),
+        ));
+    }
+    let mut body = vec![0; len];
+    input.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+fn write_message(output: &mut impl Write, msg: &Value) -> io::Result<()> {
+    let body = serde_json::to_vec(msg)?;
+    write!(output, "Content-Length: {}\r\n\r\n", body.len())?;
+    output.write_all(&body)?;
+    output.flush()
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+struct Position {
+    line: u32,
+    character: u32,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+struct Range {
+    start: Position,
+    end: Position,
+}
+
+#[derive(Deserialize)]
+struct TextDocumentIdentifier {
+    uri: String,
+}
+
+#[derive(Deserialize)]
+struct InitializeParams {
+    #[serde(rename = "rootUri")]
+    root_uri: Option<String>,
+    #[serde(rename = "rootPath")]
+    root_path: Option<String>,
+    #[serde(rename = "initializationOptions")]
+    initialization_options: Option<InitializationOptions>,
+}
+
+/// the parser options, like the corresponding command line options of `crulz`
+#[derive(Deserialize)]
+struct InitializationOptions {
+    escc: Option<String>,
+    #[serde(rename = "passEscc", default)]
+    pass_escc: bool,
+    #[serde(rename = "strictMarkers")]
+    strict_markers: Option<String>,
+    #[serde(rename = "looseMarkers")]
+    loose_markers: Option<String>,
+    #[serde(rename = "argSigil")]
+    arg_sigil: Option<char>,
+}
+
+impl InitializationOptions {
+    fn parser_opts(self) -> Result<ParserOptions, String> {
+        let markers = |name: &str, x: String| match *x.as_bytes() {
+            [begin, end] => Ok(ScopeMarkers { begin, end }),
+            _ => Err(format!(
+                "{} expects exactly two ASCII characters, got '{}'",
+                name, x
+            )),
+        };
+        let mut ret = ParserOptions {
+            pass_escc: self.pass_escc,
+            ..Default::default()
+        };
+        if let Some(x) = self.escc {
+            ret.escc = x.into_bytes();
+        }
+        if let Some(x) = self.strict_markers {
+            ret.strict_markers = markers("strictMarkers", x)?;
+        }
+        if let Some(x) = self.loose_markers {
+            ret.loose_markers = markers("looseMarkers", x)?;
+        }
+        if let Some(x) = self.arg_sigil {
+            if !x.is_ascii() {
+                return Err(format!("argSigil expects an ASCII character, got '{}'", x));
+            }
+            ret.arg_sigil = x as u8;
+        }
+        ret.validate()
+            .map_err(|e| format!("invalid parser options: {}", e))?;
+        Ok(ret)
+    }
+}
+
+#[derive(Deserialize)]
+struct TextDocumentItem {
+    uri: String,
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct DidOpenParams {
+    #[serde(rename = "textDocument")]
+    text_document: TextDocumentItem,
+}
+
+#[derive(Deserialize)]
+struct ContentChange {
+    range: Option<Range>,
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct DidChangeParams {
+    #[serde(rename = "textDocument")]
+    text_document: TextDocumentIdentifier,
+    #[serde(rename = "contentChanges")]
+    content_changes: Vec<ContentChange>,
+}
+
+#[derive(Deserialize)]
+struct DidCloseParams {
+    #[serde(rename = "textDocument")]
+    text_document: TextDocumentIdentifier,
+}
+
+#[derive(Deserialize)]
+struct PositionParams {
+    #[serde(rename = "textDocument")]
+    text_document: TextDocumentIdentifier,
+    position: Position,
+}
+
+/// converts an LSP position (with UTF-16 based columns) into a byte offset,
+/// positions after the end of a line are clamped to the end of the line
+fn offset_of(text: &[u8], pos: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..pos.line {
+        match text[line_start..].find_byte(b'\n') {
+            Some(x) => line_start += x + 1,
+            None => return text.len(),
+        }
+    }
+    let line_end = text[line_start..]
+        .find_byte(b'\n')
+        .map_or(text.len(), |x| line_start + x);
+    let mut units = 0;
+    for (start, _, c) in text[line_start..line_end].char_indices() {
+        if units >= pos.character {
+            return line_start + start;
+        }
+        units += c.len_utf16() as u32;
+    }
+    line_end
+}
+
+fn position_of(text: &[u8], offset: usize) -> Position {
+    let before = &text[..offset];
+    let line_start = before.rfind_byte(b'\n').map_or(0, |x| x + 1);
+    Position {
+        line: before.iter().filter(|&&i| i == b'\n').count() as u32,
+        character: before[line_start..]
+            .chars()
+            .map(|c| c.len_utf16() as u32)
+            .sum(),
+    }
+}
+
+fn range_of(text: &[u8], span: &std::ops::Range<usize>) -> Value {
+    let pos = |x| {
+        let p = position_of(text, x);
+        json!({ "line": p.line, "character": p.character })
+    };
+    json!({ "start": pos(span.start), "end": pos(span.end) })
+}
+
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?.as_bytes();
+    let mut ret = Vec::with_capacity(path.len());
+    let mut it = path.iter();
+    while let Some(&i) = it.next() {
+        if i == b'%' {
+            let hex = std::str::from_utf8(it.as_slice().get(..2)?).ok()?;
+            ret.push(u8::from_str_radix(hex, 16).ok()?);
+            it.nth(1);
+        } else {
+            ret.push(i);
+        }
+    }
+    Some(PathBuf::from(String::from_utf8(ret).ok()?))
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let path = std::env::current_dir()
+        .map(|cwd| cwd.join(path))
+        .unwrap_or_else(|_| path.to_path_buf());
+    let mut ret = "file://".to_string();
+    for &i in path.to_string_lossy().as_bytes() {
+        if i.is_ascii_alphanumeric() || b"/-_.~".contains(&i) {
+            ret.push(char::from(i));
+        } else {
+            ret.push_str(&format!("%{:02X}", i));
+        }
+    }
+    ret
+}
+
+// === server
+
+struct OpenFile {
+    doc: Document,
+    symbols: Symbols,
+    eval: Evaluation,
+}
+
+impl OpenFile {
+    fn new(doc: Document, sandbox: &Sandbox, loader: &Arc<dyn FileLoader>) -> Self {
+        let mut ret = Self {
+            doc,
+            symbols: Symbols::default(),
+            eval: Evaluation::default(),
+        };
+        ret.analyze(sandbox, loader);
+        ret
+    }
+
+    fn analyze(&mut self, sandbox: &Sandbox, loader: &Arc<dyn FileLoader>) {
+        self.symbols = Symbols::scan(self.doc.source(), self.doc.opts());
+        self.eval = Evaluation::run(&self.doc, sandbox, loader);
+    }
+
+    fn diagnostics(&self, uri: &str) -> Value {
+        let source = self.doc.source();
+        let opts = self.doc.opts();
+        let mut ret = Vec::new();
+        for e in self.doc.errors() {
+            let mut message = e.detail.to_string();
+            if let Some(x) = e.help(source, opts) {
+                message += "\nhelp: ";
+                message += &x;
+            }
+            let related: Vec<_> = e
+                .opening
+                .iter()
+                .map(|x| {
+                    json!({
+                        "location": { "uri": uri, "range": range_of(source, x) },
+                        "message": "unclosed delimiter",
+                    })
+                })
+                .collect();
+            ret.push(json!({
+                "range": range_of(source, &e.offending),
+                "severity": 1,
+                "source": "crulz",
+                "message": message,
+                "relatedInformation": related,
+            }));
+        }
+        for i in &self.eval.diagnostics {
+            ret.push(json!({
+                "range": range_of(source, &i.span),
+                "severity": i.severity as u8,
+                "source": "crulz",
+                "message": i.message,
+            }));
+        }
+        json!({ "uri": uri, "diagnostics": ret })
+    }
+}
+
+pub struct Server {
+    opts: ParserOptions,
+    files: HashMap<String, OpenFile>,
+    /// restricts the file system accesses of the evaluations, documents are
+    /// evaluated after each change, thus only the workspace root is accessible
+    /// (or nothing, if the client didn't send one)
+    sandbox: Sandbox,
+    /// used for all file system accesses, i.e. for included files
+    loader: Arc<dyn FileLoader>,
+    shutdown: bool,
+}
+
+impl Server {
+    pub fn new(opts: ParserOptions) -> Self {
+        Self {
+            opts,
+            files: HashMap::new(),
+            sandbox: Sandbox::DenyAll,
+            loader: Arc::new(StdFileLoader),
+            shutdown: false,
+        }
+    }
+
+    /// replaces the file loader, e.g. to serve included files from memory
+    pub fn with_loader(mut self, loader: Arc<dyn FileLoader>) -> Self {
+        self.loader = loader;
+        self
+    }
+
+    /// processes messages from `input` until an `exit` notification or EOF is received
+    ///
+    /// # Return value
+    /// * `Ok(true)` if a `shutdown` request was received before
+    pub fn run(&mut self, mut input: impl BufRead, mut output: impl Write) -> io::Result<bool> {
+        let mut out = Vec::new();
+        while let Some(body) = read_message(&mut input)? {
+            let exit = match serde_json::from_slice::<Value>(&body) {
+                Ok(msg) => self.handle(msg, &mut out),
+                Err(e) => {
+                    out.push(json!({
+                        "jsonrpc": "2.0",
+                        "id": null,
+                        "error": { "code": PARSE_ERROR, "message": e.to_string() },
+                    }));
+                    false
+                }
+            };
+            for i in out.drain(..) {
+                write_message(&mut output, &i)?;
+            }
+            if exit {
+                break;
+            }
+        }
+        Ok(self.shutdown)
+    }
+
+    /// handles a single message, returns `true` if the server should exit
+    fn handle(&mut self, msg: Value, out: &mut Vec<Value>) -> bool {
+        let method = msg["method"].as_str().unwrap_or_default().to_string();
+        let params = msg.get("params").cloned().unwrap_or(Value::Null);
+        match msg.get("id").cloned() {
+            // request
+            Some(id) => {
+                let res = if self.shutdown {
+                    Err(RpcError {
+                        code: INVALID_REQUEST,
+                        message: "server is shutting down".to_string(),
+                    })
+                } else {
+                    self.handle_request(&method, params)
+                };
+                out.push(match res {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err(e) => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": e.code, "message": e.message },
+                    }),
+                });
+                false
+            }
+            // notification
+            None => {
+                if method == "exit" {
+                    return true;
+                }
+                if let Err(e) = self.handle_notification(&method, params, out) {
+                    eprintln!("crulz-lsp: ERROR: {}: {}", method, e.message);
+                }
+                false
+            }
+        }
+    }
+
+    fn handle_request(&mut self, method: &str, params: Value) -> Result<Value, RpcError> {
+        match method {
+            "initialize" => {
+                let params: InitializeParams =
+                    serde_json::from_value(params).map_err(invalid_params)?;
+                let root = match params.root_uri {
+                    Some(uri) => uri_to_path(&uri),
+                    None => params.root_path.map(PathBuf::from),
+                };
+                if let Some(root) = root {
+                    self.sandbox = Sandbox::AllowRoots(vec![root]);
+                }
+                if let Some(x) = params.initialization_options {
+                    self.opts = x.parser_opts().map_err(|message| RpcError {
+                        code: INVALID_PARAMS,
+                        message,
+                    })?;
+                }
+                Ok(json!({
+                "capabilities": {
+                    // incremental
+                    "textDocumentSync": { "openClose": true, "change": 2 },
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": {
+                        "triggerCharacters": [self.opts.escc.to_str_lossy()],
+                    },
+                },
+                "serverInfo": { "name": "crulz-lsp", "version": env!("CARGO_PKG_VERSION") },
+                }))
+            }
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+            "textDocument/definition" => {
+                let params: PositionParams =
+                    serde_json::from_value(params).map_err(invalid_params)?;
+                Ok(self.definition(&params))
+            }
+            "textDocument/hover" => {
+                let params: PositionParams =
+                    serde_json::from_value(params).map_err(invalid_params)?;
+                Ok(self.hover(&params))
+            }
+            "textDocument/completion" => {
+                let params: PositionParams =
+                    serde_json::from_value(params).map_err(invalid_params)?;
+                Ok(self.completion(&params))
+            }
+            _ => Err(RpcError {
+                code: METHOD_NOT_FOUND,
+                message: format!("unknown method '{}'", method),
+            }),
+        }
+    }
+
+    fn handle_notification(
+        &mut self,
+        method: &str,
+        params: Value,
+        out: &mut Vec<Value>,
+    ) -> Result<(), RpcError> {
+        let uri = match method {
+            "textDocument/didOpen" => {
+                let params: DidOpenParams =
+                    serde_json::from_value(params).map_err(invalid_params)?;
+                let item = params.text_document;
+                let doc = Document::parse(item.text.into_bytes(), self.opts.clone());
+                self.files.insert(
+                    item.uri.clone(),
+                    OpenFile::new(doc, &self.sandbox, &self.loader),
+                );
+                item.uri
+            }
+            "textDocument/didChange" => {
+                let params: DidChangeParams =
+                    serde_json::from_value(params).map_err(invalid_params)?;
+                let uri = params.text_document.uri;
+                let file = self.files.get_mut(&uri).ok_or_else(|| RpcError {
+                    code: INVALID_PARAMS,
+                    message: format!("unknown document '{}'", uri),
+                })?;
+                for i in params.content_changes {
+                    match i.range {
+                        Some(range) => {
+                            let source = file.doc.source();
+                            let start = offset_of(source, range.start);
+                            let end = offset_of(source, range.end).max(start);
+                            file.doc.edit(start..end, i.text.as_bytes());
+                        }
+                        None => file.doc = Document::parse(i.text.into_bytes(), self.opts.clone()),
+                    }
+                }
+                file.analyze(&self.sandbox, &self.loader);
+                uri
+            }
+            "textDocument/didClose" => {
+                let params: DidCloseParams =
+                    serde_json::from_value(params).map_err(invalid_params)?;
+                let uri = params.text_document.uri;
+                self.files.remove(&uri);
+                out.push(json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": { "uri": uri, "diagnostics": [] },
+                }));
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        out.push(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": self.files[&uri].diagnostics(&uri),
+        }));
+        Ok(())
+    }
+
+    /// the open file and the name of the called macro at the given position
+    fn call_at(&self, params: &PositionParams) -> Option<(&OpenFile, &analysis::Call)> {
+        let file = self.files.get(&params.text_document.uri)?;
+        let offset = offset_of(file.doc.source(), params.position);
+        Some((file, file.symbols.call_at(offset)?))
+    }
+
+    /// reads a file which isn't open, if the sandbox allows it
+    fn load(&self, path: &Path) -> Option<Vec<u8>> {
+        let path = self.sandbox.check(path, &*self.loader).ok()?;
+        self.loader.load(&path).ok()
+    }
+
+    /// collects the definition sites of `name` in the given file and,
+    /// recursively, in all files included by it
+    fn collect_defs(
+        &self,
+        uri: String,
+        name: &[u8],
+        visited: &mut HashSet<String>,
+        ret: &mut Vec<Value>,
+    ) {
+        if !visited.insert(uri.clone()) {
+            return;
+        }
+        // prefer the state of open files over the file system
+        let (source, symbols) = match self.files.get(&uri) {
+            Some(x) => (x.doc.source().to_vec(), x.symbols.clone()),
+            None => match uri_to_path(&uri).and_then(|x| self.load(&x)) {
+                Some(x) => {
+                    let symbols = Symbols::scan(&x, &self.opts);
+                    (x, symbols)
+                }
+                None => return,
+            },
+        };
+        for i in symbols.defs.iter().filter(|i| i.name == name) {
+            ret.push(json!({ "uri": uri, "range": range_of(&source, &i.span) }));
+        }
+        for i in &symbols.includes {
+            // include paths are resolved like the interpreter does
+            if let Ok(x) = std::str::from_utf8(i) {
+                self.collect_defs(path_to_uri(Path::new(x)), name, visited, ret);
+            }
+        }
+    }
+
+    fn definition(&self, params: &PositionParams) -> Value {
+        let call = match self.call_at(params) {
+            Some((_, call)) => call,
+            None => return Value::Null,
+        };
+        let mut ret = Vec::new();
+        self.collect_defs(
+            params.text_document.uri.clone(),
+            &call.name,
+            &mut HashSet::new(),
+            &mut ret,
+        );
+        Value::Array(ret)
+    }
+
+    fn hover(&self, params: &PositionParams) -> Value {
+        let (file, call) = match self.call_at(params) {
+            Some(x) => x,
+            None => return Value::Null,
+        };
+        let opts = file.doc.opts();
+        let name = call.name.to_str_lossy();
+        let escc = opts.escc.to_str_lossy();
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        let contents = if let Some((argc, body)) = file.eval.defs.get(&call.name) {
+            let mut fmt_body = Vec::new();
+            body.fmt(&mut fmt_body, opts);
+            let argc = if argc.params.is_some() {
+                format!("parameters {}", argc)
+            } else if argc.variadic {
+                format!("{} arguments", argc)
+            } else {
+                format!("{} argument{}", argc, plural(argc.fixed))
+            };
+            format!(
+                "**{}{}**: {}\n\n```\n{}\n```",
+                escc,
+                name,
+                argc,
+                fmt_body.to_str_lossy()
+            )
+        } else if let Some(argc) = file.eval.procdefs.get(&call.name) {
+            match argc {
+                Some(n) => format!(
+                    "**{}{}**: built-in, {} argument{}",
+                    escc,
+                    name,
+                    n,
+                    plural(*n)
+                ),
+                None => format!("**{}{}**: built-in, variadic", escc, name),
+            }
+        } else {
+            return Value::Null;
+        };
+        json!({
+            "contents": { "kind": "markdown", "value": contents },
+            "range": range_of(file.doc.source(), &call.name_span),
+        })
+    }
+
+    fn completion(&self, params: &PositionParams) -> Value {
+        let file = match self.files.get(&params.text_document.uri) {
+            Some(x) => x,
+            None => return Value::Null,
+        };
+        let mut defs: Vec<_> = file.eval.defs.iter().collect();
+        defs.sort_by(|a, b| a.0.cmp(b.0));
+        let mut procdefs: Vec<_> = file.eval.procdefs.iter().collect();
+        procdefs.sort_by(|a, b| a.0.cmp(b.0));
+
+        // completion item kinds: 3 = function, 14 = keyword
+        let ret: Vec<_> = defs
+            .into_iter()
+            .map(|(name, (argc, _))| {
+                json!({
+                    "label": name.to_str_lossy(),
+                    "kind": 3,
+                    "detail": if argc.params.is_some() {
+                        format!("parameters {}", argc)
+                    } else {
+                        format!("{} arguments", argc)
+                    },
+                })
+            })
+            .chain(procdefs.into_iter().map(|(name, _)| {
+                json!({
+                    "label": name.to_str_lossy(),
+                    "kind": 14,
+                    "detail": "built-in",
+                })
+            }))
+            .collect();
+        Value::Array(ret)
+    }
+}
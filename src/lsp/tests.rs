@@ -0,0 +1,370 @@
+#![cfg(test)]
+
+use super::*;
+
+/// runs a session with the given messages and returns the
+/// exit status and all messages sent by the server
+fn session(msgs: &[Value]) -> (bool, Vec<Value>) {
+    let mut input = Vec::new();
+    for i in msgs {
+        write_message(&mut input, i).unwrap();
+    }
+    let mut output = Vec::new();
+    let clean = Server::new(ParserOptions::default())
+        .run(&input[..], &mut output)
+        .unwrap();
+
+    let mut ret = Vec::new();
+    let mut output = &output[..];
+    while let Some(body) = read_message(&mut output).unwrap() {
+        ret.push(serde_json::from_slice(&body).unwrap());
+    }
+    (clean, ret)
+}
+
+fn request(id: u64, method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+fn did_open(uri: &str, text: &str) -> Value {
+    notification(
+        "textDocument/didOpen",
+        json!({ "textDocument": { "uri": uri, "languageId": "crulz", "version": 1, "text": text } }),
+    )
+}
+
+fn at(id: u64, method: &str, uri: &str, line: u32, character: u32) -> Value {
+    request(
+        id,
+        method,
+        json!({
+            "textDocument": { "uri": uri },
+            "position": { "line": line, "character": character },
+        }),
+    )
+}
+
+fn response(msgs: &[Value], id: u64) -> &Value {
+    msgs.iter()
+        .find(|i| i["id"] == json!(id))
+        .expect("missing response")
+}
+
+/// the diagnostics of all `publishDiagnostics` notifications, in order
+fn diagnostics(msgs: &[Value]) -> Vec<&Vec<Value>> {
+    msgs.iter()
+        .filter(|i| i["method"] == "textDocument/publishDiagnostics")
+        .map(|i| i["params"]["diagnostics"].as_array().unwrap())
+        .collect()
+}
+
+fn range(sl: u32, sc: u32, el: u32, ec: u32) -> Value {
+    json!({
+        "start": { "line": sl, "character": sc },
+        "end": { "line": el, "character": ec },
+    })
+}
+
+const URI: &str = "file:///tmp/crulz-lsp-test.crulz";
+
+#[test]
+fn test_lifecycle() {
+    let (clean, msgs) = session(&[
+        request(1, "initialize", json!({ "capabilities": {} })),
+        notification("initialized", json!({})),
+        request(2, "textDocument/unknown", json!({})),
+        request(3, "shutdown", Value::Null),
+        request(4, "textDocument/hover", json!({})),
+        notification("exit", Value::Null),
+    ]);
+    assert!(clean);
+    let caps = &response(&msgs, 1)["result"]["capabilities"];
+    assert_eq!(caps["textDocumentSync"]["change"], 2);
+    assert_eq!(
+        caps["completionProvider"]["triggerCharacters"],
+        json!(["\\"])
+    );
+    assert_eq!(response(&msgs, 2)["error"]["code"], METHOD_NOT_FOUND);
+    assert_eq!(response(&msgs, 3)["result"], Value::Null);
+    assert_eq!(response(&msgs, 4)["error"]["code"], INVALID_REQUEST);
+
+    // exit without shutdown
+    let (clean, msgs) = session(&[notification("exit", Value::Null)]);
+    assert!(!clean);
+    assert!(msgs.is_empty());
+}
+
+#[test]
+fn test_diagnostics() {
+    let (_, msgs) = session(&[
+        did_open(URI, "\\def(a 0 x)\n\\(a) \\(b)\n\\(add 1"),
+        // fix the syntax error by appending the missing ')'
+        notification(
+            "textDocument/didChange",
+            json!({
+                "textDocument": { "uri": URI, "version": 2 },
+                "contentChanges": [{ "range": range(2, 7, 2, 7), "text": " 2)" }],
+            }),
+        ),
+        // replace the whole document
+        notification(
+            "textDocument/didChange",
+            json!({
+                "textDocument": { "uri": URI, "version": 3 },
+                "contentChanges": [{ "text": "\\(a)" }],
+            }),
+        ),
+        notification(
+            "textDocument/didClose",
+            json!({ "textDocument": { "uri": URI } }),
+        ),
+    ]);
+    let diags = diagnostics(&msgs);
+    assert_eq!(diags.len(), 4);
+
+    // syntax error and unknown macro
+    assert_eq!(diags[0].len(), 2);
+    assert_eq!(diags[0][0]["severity"], 1);
+    assert_eq!(diags[0][0]["range"], range(2, 7, 2, 7));
+    assert_eq!(
+        diags[0][0]["relatedInformation"][0]["location"]["range"],
+        range(2, 0, 2, 2)
+    );
+    assert!(diags[0][0]["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("expected ')' instead\nhelp: "));
+    assert_eq!(diags[0][1]["severity"], 2);
+    assert_eq!(diags[0][1]["range"], range(1, 5, 1, 9));
+    assert_eq!(diags[0][1]["message"], "unable to evaluate '\\b'");
+
+    assert_eq!(diags[1].len(), 1);
+    assert_eq!(diags[1][0]["message"], "unable to evaluate '\\b'");
+    assert_eq!(diags[2][0]["message"], "unable to evaluate '\\a'");
+    assert!(diags[3].is_empty());
+}
+
+#[test]
+fn test_eval_failure() {
+    let (_, msgs) = session(&[did_open(URI, "x\\lambda(y 1)")]);
+    let diags = diagnostics(&msgs);
+    assert_eq!(diags[0].len(), 1);
+    assert_eq!(diags[0][0]["severity"], 1);
+    assert_eq!(diags[0][0]["range"], range(0, 1, 0, 13));
+    assert_eq!(
+        diags[0][0]["message"],
+        "evaluation failed: expected number as argc"
+    );
+}
+
+#[test]
+fn test_eval_limits() {
+    let (_, msgs) = session(&[did_open(URI, "\\def-lazy(a 1 \\(a \\(add $0 1)))\\(a 0)")]);
+    let diags = diagnostics(&msgs);
+    assert_eq!(diags[0].len(), 1);
+    assert_eq!(diags[0][0]["range"], range(0, 31, 0, 37));
+    assert_eq!(
+        diags[0][0]["message"],
+        "evaluation failed: evaluation step limit (10000) exceeded in macro chain: a"
+    );
+
+    // growing nesting is stopped by the recursion limit
+    let (_, msgs) = session(&[did_open(URI, "\\def-lazy(a 0 \\(a)x)\\(a)")]);
+    let diags = diagnostics(&msgs);
+    assert_eq!(diags[0][0]["range"], range(0, 20, 0, 24));
+    assert_eq!(
+        diags[0][0]["message"],
+        "evaluation failed: recursion depth limit (256) exceeded"
+    );
+}
+
+#[test]
+fn test_parser_options() {
+    let init = |opts: Value| request(1, "initialize", json!({ "initializationOptions": opts }));
+    let (_, msgs) = session(&[
+        init(json!({ "escc": "@", "strictMarkers": "[]", "argSigil": "%" })),
+        did_open(URI, "@def-lazy[x 1 <%0>]@[x a]"),
+        at(2, "textDocument/definition", URI, 0, 21),
+    ]);
+    assert_eq!(
+        response(&msgs, 1)["result"]["capabilities"]["completionProvider"]["triggerCharacters"],
+        json!(["@"])
+    );
+    assert!(diagnostics(&msgs)[0].is_empty());
+    assert_eq!(
+        response(&msgs, 2)["result"],
+        json!([{ "uri": URI, "range": range(0, 0, 0, 19) }])
+    );
+
+    let (_, msgs) = session(&[init(json!({ "looseMarkers": "{" }))]);
+    assert_eq!(response(&msgs, 1)["error"]["code"], INVALID_PARAMS);
+    assert_eq!(
+        response(&msgs, 1)["error"]["message"],
+        "looseMarkers expects exactly two ASCII characters, got '{'"
+    );
+}
+
+#[test]
+fn test_sandbox() {
+    let dir = std::env::temp_dir().join(format!("crulz-lsp-sandbox-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("root")).unwrap();
+    let inner = dir.join("root/inner.crulz");
+    let outer = dir.join("outer.crulz");
+    std::fs::write(&inner, "\\def(f 0 inner)").unwrap();
+    std::fs::write(&outer, "\\def(g 0 outer)").unwrap();
+    let open = |path: &Path| did_open(URI, &format!("\\include({})", path.display()));
+    let both = format!(
+        "\\include({})\\include({})\n\\(f)\\(g)",
+        inner.display(),
+        outer.display()
+    );
+
+    let (_, msgs) = session(&[
+        request(
+            1,
+            "initialize",
+            json!({ "rootUri": path_to_uri(&dir.join("root")) }),
+        ),
+        open(&inner),
+        open(&outer),
+        did_open(URI, &both),
+        at(2, "textDocument/definition", URI, 1, 2),
+        at(3, "textDocument/definition", URI, 1, 6),
+    ]);
+    let (_, denied) = session(&[open(&inner)]);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let diags = diagnostics(&msgs);
+    assert!(diags[0].is_empty());
+    assert_eq!(
+        diags[1][0]["message"],
+        format!(
+            "evaluation failed: access to '{}' denied by sandbox: path is outside of the allowed roots",
+            outer.display()
+        )
+    );
+    // definitions are only looked up in accessible files
+    assert_eq!(
+        response(&msgs, 2)["result"],
+        json!([{ "uri": path_to_uri(&inner), "range": range(0, 0, 0, 15) }])
+    );
+    assert_eq!(response(&msgs, 3)["result"], json!([]));
+
+    // without a workspace root, no files are accessible
+    assert_eq!(
+        diagnostics(&denied)[0][0]["message"],
+        format!(
+            "evaluation failed: access to '{}' denied by sandbox: file system access is disabled",
+            inner.display()
+        )
+    );
+}
+
+#[test]
+fn test_message_size() {
+    let input = format!("Content-Length: {}\r\n\r\n", MAX_MESSAGE_SIZE + 1);
+    let e = read_message(&mut input.as_bytes()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+        e.to_string(),
+        format!(
+            "Content-Length {} exceeds the maximum message size ({})",
+            MAX_MESSAGE_SIZE + 1,
+            MAX_MESSAGE_SIZE
+        )
+    );
+}
+
+#[test]
+fn test_definition() {
+    let dir = std::env::temp_dir().join(format!("crulz-lsp-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let incl = dir.join("incl.crulz");
+    std::fs::write(&incl, "\n  \\(def-lazy greet 1 {Hello, $0!})").unwrap();
+    let text = format!(
+        "\\include({})\n\\suppress(\\def(x 0 y))\n\\(greet \\x)",
+        incl.display()
+    );
+
+    let (_, msgs) = session(&[
+        request(0, "initialize", json!({ "rootUri": path_to_uri(&dir) })),
+        did_open(URI, &text),
+        at(1, "textDocument/definition", URI, 2, 3),
+        at(2, "textDocument/definition", URI, 2, 9),
+        // no call at this position
+        at(3, "textDocument/definition", URI, 1, 0),
+        at(4, "textDocument/hover", URI, 2, 9),
+        at(5, "textDocument/hover", URI, 2, 2),
+        at(6, "textDocument/hover", URI, 1, 3),
+        at(7, "textDocument/completion", URI, 2, 0),
+    ]);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(diagnostics(&msgs)[0].is_empty());
+    assert_eq!(
+        response(&msgs, 1)["result"],
+        json!([{ "uri": path_to_uri(&incl), "range": range(1, 2, 1, 34) }])
+    );
+    assert_eq!(
+        response(&msgs, 2)["result"],
+        json!([{ "uri": URI, "range": range(1, 10, 1, 21) }])
+    );
+    assert_eq!(response(&msgs, 3)["result"], Value::Null);
+
+    let hover = &response(&msgs, 4)["result"];
+    assert_eq!(
+        hover["contents"]["value"],
+        "**\\x**: 0 arguments\n\n```\ny\n```"
+    );
+    assert_eq!(hover["range"], range(2, 9, 2, 10));
+    assert_eq!(
+        response(&msgs, 5)["result"]["contents"]["value"],
+        "**\\greet**: 1 argument\n\n```\nHello, $0!\n```"
+    );
+    assert_eq!(
+        response(&msgs, 6)["result"]["contents"]["value"],
+        "**\\suppress**: built-in, variadic"
+    );
+
+    let labels: Vec<_> = response(&msgs, 7)["result"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|i| i["label"].as_str().unwrap())
+        .collect();
+    assert_eq!(&labels[..3], ["greet", "x", "add"]);
+    assert!(labels.contains(&"def-lazy"));
+}
+
+#[test]
+fn test_positions() {
+    let text = "a\u{e4}\u{1f600}b\nc".as_bytes();
+    let pos = |line, character| Position { line, character };
+    for (offset, p) in [
+        (0, pos(0, 0)),
+        (1, pos(0, 1)),
+        (3, pos(0, 2)),
+        (7, pos(0, 4)),
+        (8, pos(0, 5)),
+        (9, pos(1, 0)),
+        (10, pos(1, 1)),
+    ] {
+        assert_eq!(position_of(text, offset), p);
+        assert_eq!(offset_of(text, p), offset);
+    }
+    // clamped to the end of the line / text
+    assert_eq!(offset_of(text, pos(0, 100)), 8);
+    assert_eq!(offset_of(text, pos(5, 0)), text.len());
+}
+
+#[test]
+fn test_uris() {
+    let path = Path::new("/tmp/a b/\u{e4}.crulz");
+    let uri = path_to_uri(path);
+    assert_eq!(uri, "file:///tmp/a%20b/%C3%A4.crulz");
+    assert_eq!(uri_to_path(&uri).as_deref(), Some(path));
+}
@@ -38,74 +38,525 @@ macro_rules! timing_of {
     }};
 }
 
-#[derive(Debug, Options)]
-struct CrulzOptions {
-    #[options(free)]
-    inputs: Vec<String>,
+/// declares the options struct of a (sub-)command, with the parser options
+/// which are shared by all of them appended to the given fields
+macro_rules! with_parser_options {
+    ($(#[$attr:meta])* struct $name:ident { $($fields:tt)* }) => {
+        $(#[$attr])*
+        struct $name {
+            $($fields)*
 
-    #[options(help = "prints help information")]
-    help: bool,
+            #[options(help = "sets the escape sequence (default: '\\')")]
+            escc: Option<String>,
 
-    #[options(help = "sets the escape character")]
-    escc: Option<u8>,
+            #[options(
+                no_short,
+                help = "sets the begin and end markers of strict groups (default: '()')"
+            )]
+            strict_markers: Option<String>,
 
-    #[options(help = "enable direct pass-through for double escape character")]
+            #[options(
+                no_short,
+                help = "sets the begin and end markers of loose groups (default: '{}')"
+            )]
+            loose_markers: Option<String>,
+
+            #[options(no_short, help = "sets the argument sigil (default: '$')")]
+            arg_sigil: Option<char>,
+        }
+
+        impl $name {
+            /// constructs the parser options, exits on failure
+            fn parser_opts(&self, pass_escc: bool) -> parser::Options {
+                make_parser_opts(
+                    self.escc.as_deref(),
+                    pass_escc,
+                    self.strict_markers.as_deref(),
+                    self.loose_markers.as_deref(),
+                    self.arg_sigil,
+                )
+            }
+        }
+    };
+}
+
+with_parser_options! {
+    #[derive(Debug, Options)]
+    struct CrulzOptions {
+        #[options(free)]
+        inputs: Vec<String>,
+
+        #[options(help = "prints help information")]
+        help: bool,
+
+        #[options(help = "enable direct pass-through for double escape character")]
+        pass_escc: bool,
+
+        #[options(count, help = "sets the level of verbosity", short = "v")]
+        verbose: u8,
+
+        #[options(help = "output various timings / perf stats")]
+        timings: bool,
+
+        #[options(help = "suppress output of evaluated data")]
+        quiet: bool,
+
+        #[options(
+            short = "j",
+            meta = "N",
+            help = "evaluates multiple input files with up to N threads (default: 1), \
+                    the results are written in the given order"
+        )]
+        jobs: Option<usize>,
+
+        #[cfg(feature = "compile")]
+        #[options(
+            help = "each given element has the format '$1=$2' -> map '$1=$2' includes of $1 to $2"
+        )]
+        map_to_compilate: Vec<String>,
+
+        #[cfg(feature = "compile")]
+        #[options(
+            help = "if set, writes the packed processed output including defines to the given output file"
+        )]
+        compile_output: Option<PathBuf>,
+
+        #[options(help = "if set, writes the evaluated data to the given file")]
+        output: Option<PathBuf>,
+
+        #[options(no_short, help = "limits the total count of command evaluations")]
+        max_steps: Option<usize>,
+
+        #[options(
+            no_short,
+            help = "limits the nesting depth of the evaluation (default: 256, 0 disables the limit)"
+        )]
+        max_depth: Option<usize>,
+
+        #[options(
+            no_short,
+            help = "limits the size of the output (measured in AST complexity)"
+        )]
+        max_output: Option<usize>,
+
+        #[options(
+            no_short,
+            help = "denies all file system accesses of the evaluated code"
+        )]
+        sandbox: bool,
+
+        #[options(
+            no_short,
+            meta = "DIR",
+            help = "like --sandbox, but allows accesses inside the given directory"
+        )]
+        sandbox_root: Vec<PathBuf>,
+
+        #[options(no_short, help = "prints each expansion step to stderr")]
+        trace: bool,
+
+        #[options(
+            no_short,
+            meta = "FORMAT",
+            help = "sets the format of the trace, 'text' or 'json' (implies --trace)"
+        )]
+        trace_format: Option<String>,
+
+        #[options(
+            no_short,
+            meta = "FILE",
+            help = "writes the trace to the given file (implies --trace)"
+        )]
+        trace_output: Option<PathBuf>,
+
+        #[options(
+            no_short,
+            long = "break",
+            meta = "NAME",
+            help = "stops before each evaluation of the given macro and waits for commands on stdin"
+        )]
+        breakpoints: Vec<String>,
+
+        #[options(
+            no_short,
+            help = "prints per-macro statistics (calls, times, complexity growth) to stderr"
+        )]
+        profile: bool,
+
+        #[options(
+            no_short,
+            meta = "FILE",
+            help = "writes the per-macro self times as folded stacks (for flamegraphs) to the given file"
+        )]
+        profile_folded: Option<PathBuf>,
+    }
+}
+
+with_parser_options! {
+    #[derive(Debug, Options)]
+    struct FmtOptions {
+        #[options(free)]
+        inputs: Vec<PathBuf>,
+
+        #[options(help = "prints help information")]
+        help: bool,
+
+        #[options(help = "only check if the given files are formatted, don't modify them")]
+        check: bool,
+    }
+}
+
+with_parser_options! {
+    #[derive(Debug, Options)]
+    struct HighlightOptions {
+        #[options(free)]
+        inputs: Vec<PathBuf>,
+
+        #[options(help = "prints help information")]
+        help: bool,
+
+        #[options(no_short, help = "renders HTML instead of ANSI escape codes")]
+        html: bool,
+    }
+}
+
+fn parse_scope_markers(name: &str, x: &str) -> parser::ScopeMarkers {
+    match x.as_bytes() {
+        &[begin, end] => parser::ScopeMarkers { begin, end },
+        _ => {
+            eprintln!(
+                "crulz: ERROR: --{} expects exactly two ASCII characters, got '{}'",
+                name, x
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// constructs the parser options from the command line arguments, exits on failure
+fn make_parser_opts(
+    escc: Option<&str>,
     pass_escc: bool,
+    strict_markers: Option<&str>,
+    loose_markers: Option<&str>,
+    arg_sigil: Option<char>,
+) -> parser::Options {
+    let mut ret = parser::Options {
+        pass_escc,
+        ..Default::default()
+    };
+    if let Some(x) = escc {
+        ret.escc = x.as_bytes().to_vec();
+    }
+    if let Some(x) = strict_markers {
+        ret.strict_markers = parse_scope_markers("strict-markers", x);
+    }
+    if let Some(x) = loose_markers {
+        ret.loose_markers = parse_scope_markers("loose-markers", x);
+    }
+    if let Some(x) = arg_sigil {
+        if !x.is_ascii() {
+            eprintln!(
+                "crulz: ERROR: --arg-sigil expects an ASCII character, got '{}'",
+                x
+            );
+            std::process::exit(1);
+        }
+        ret.arg_sigil = x as u8;
+    }
+    if let Err(e) = ret.validate() {
+        eprintln!("crulz: ERROR: invalid parser options: {}", e);
+        std::process::exit(1);
+    }
+    ret
+}
+
+/// sets up tracing and the stepper, if requested
+fn make_tracer(
+    opts: &CrulzOptions,
+    input_file: &str,
+    input: &[u8],
+    pars_opts: &parser::Options,
+) -> Option<Box<dyn trace::Tracer>> {
+    use trace::{SourceInfo, Stepper, TraceFormat, TraceWriter};
+
+    let source = || SourceInfo::new(input_file, input);
+    let format = match opts.trace_format.as_deref() {
+        None | Some("text") => TraceFormat::Text,
+        Some("json") => TraceFormat::Json,
+        Some(x) => {
+            eprintln!(
+                "crulz: ERROR: --trace-format expects 'text' or 'json', got '{}'",
+                x
+            );
+            std::process::exit(1);
+        }
+    };
+
+    let mut ret: Option<Box<dyn trace::Tracer>> = None;
+    if let Some(x) = &opts.trace_output {
+        let file = std::fs::File::create(x).expect("unable to create trace output file");
+        ret = Some(Box::new(
+            TraceWriter::new(io::BufWriter::new(file), format, pars_opts.clone())
+                .with_source(source()),
+        ));
+    } else if opts.trace || opts.trace_format.is_some() {
+        ret = Some(Box::new(
+            TraceWriter::new(io::stderr(), format, pars_opts.clone()).with_source(source()),
+        ));
+    }
+    if !opts.breakpoints.is_empty() {
+        let mut stepper = Stepper::new(
+            opts.breakpoints.iter().map(|i| i.as_bytes().to_vec()),
+            io::BufReader::new(io::stdin()),
+            io::stderr(),
+            pars_opts.clone(),
+        )
+        .with_source(source());
+        if let Some(x) = ret {
+            stepper = stepper.with_inner(x);
+        }
+        ret = Some(Box::new(stepper));
+    }
+    ret
+}
+
+/// prints the profile as table to stderr and/or writes the folded stacks to `folded`
+fn write_profile(prof: &profile::Profile, table: bool, folded: Option<&Path>) {
+    if table {
+        eprintln!(
+            "crulz: {}:",
+            ansi_term::Style::new().bold().paint("profile")
+        );
+        prof.write_table(io::stderr().lock())
+            .expect("unable to write profile");
+    }
+    if let Some(x) = folded {
+        let file = std::fs::File::create(x).expect("unable to create profile output file");
+        prof.write_folded(io::BufWriter::new(file))
+            .expect("unable to write profile");
+    }
+}
 
-    #[options(count, help = "sets the level of verbosity", short = "v")]
-    verbose: u8,
+/// evaluates multiple input files (see [`batch::eval_files`]) and writes
+/// their results in order into `out`, returns `false` if any of them failed
+fn eval_batch(
+    inputs: &[PathBuf],
+    jobs: usize,
+    ectx: &mut interp::EvalContext<'_>,
+    out: &mut Tee,
+    vblvl: u8,
+) -> bool {
+    use crulz::ast::Mangle as _;
 
-    #[options(help = "output various timings / perf stats")]
-    timings: bool,
+    let mut success = true;
+    let mut profile = ectx.profile.take();
+    batch::eval_files(inputs, ectx, jobs, |path, fctx, res| {
+        if let (Some(prof), Some(x)) = (&mut profile, fctx.profile) {
+            prof.merge(x);
+        }
+        match res {
+            Ok(data) => {
+                if vblvl > 0 {
+                    print_ast(
+                        &format!("AST of '{}' after evaluation", path.display()),
+                        &data,
+                    );
+                }
+                data.write_to(out, &fctx.opts)
+                    .expect("unable to write result");
+            }
+            Err(e) => {
+                eprintln!("crulz: ERROR: {}: {:#}", path.display(), e);
+                success = false;
+            }
+        }
+    });
+    ectx.profile = profile;
+    success
+}
 
-    #[options(help = "suppress output of evaluated data")]
-    quiet: bool,
+/// writes into all of its outputs
+struct Tee(Vec<Box<dyn Write>>);
 
-    #[cfg(feature = "compile")]
-    #[options(
-        help = "each given element has the format '$1=$2' -> map '$1=$2' includes of $1 to $2"
-    )]
-    map_to_compilate: Vec<String>,
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
 
-    #[cfg(feature = "compile")]
-    #[options(
-        help = "if set, writes the packed processed output including defines to the given output file"
-    )]
-    compile_output: Option<PathBuf>,
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.iter_mut().try_for_each(|i| i.write_all(buf))
+    }
 
-    #[options(help = "if set, writes the evaluated data to the given file")]
-    output: Option<PathBuf>,
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.iter_mut().try_for_each(|i| i.flush())
+    }
+}
+
+/// the outputs of the streaming and batch modes
+struct Output {
+    tee: Tee,
+    /// the temporary file and the output file it replaces in [`Output::finish`]
+    file: Option<(PathBuf, PathBuf)>,
+}
+
+impl Output {
+    /// flushes the outputs and moves the temporary file to the output file
+    /// if `success`, otherwise removes it, keeping the previous output file
+    fn finish(self, success: bool) {
+        let Output { mut tee, file } = self;
+        let flushed = tee.flush();
+        // close the temporary file before it gets renamed or removed
+        std::mem::drop(tee);
+        if let Some((tmp, dest)) = file {
+            if success && flushed.is_ok() {
+                std::fs::rename(&tmp, &dest).expect("unable to replace output file");
+            } else {
+                let _ = std::fs::remove_file(&tmp);
+            }
+        }
+        flushed.expect("unable to flush result");
+    }
+}
+
+/// opens stdout (unless quiet) and a temporary file next to the output file,
+/// which only replaces the output file once the evaluation succeeded
+fn make_output(output: Option<&Path>, quiet: bool) -> Output {
+    let mut ret = Output {
+        tee: Tee(Vec::new()),
+        file: None,
+    };
+    if let Some(x) = output {
+        let mut tmp = x.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let file = std::fs::File::create(&tmp).expect("unable to create output file");
+        ret.tee.0.push(Box::new(io::BufWriter::new(file)));
+        ret.file = Some((tmp, x.to_path_buf()));
+    }
+    if !quiet {
+        ret.tee.0.push(Box::new(io::BufWriter::new(io::stdout())));
+    }
+    ret
+}
+
+/// parses the arguments of the sub-command `$name`, exits on failure
+fn parse_subcmd_args<T: Options>(name: &str) -> T {
+    let args: Vec<_> = std::env::args().skip(2).collect();
+    match T::parse_args_default(&args) {
+        Ok(opts) if opts.help_requested() => {
+            println!("Usage: crulz {} [OPTIONS]", name);
+            println!();
+            println!("{}", T::usage());
+            std::process::exit(0);
+        }
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("crulz {}: {}", name, e);
+            std::process::exit(2);
+        }
+    }
+}
+
+fn fmt_main() {
+    let opts: FmtOptions = parse_subcmd_args("fmt");
+    let pars_opts = opts.parser_opts(false);
+
+    let mut success = true;
+    for i in &opts.inputs {
+        let (orig, formatted) = match formatter::format_file(i, &pars_opts) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("crulz: ERROR: {}: {}", i.display(), e);
+                success = false;
+                continue;
+            }
+        };
+        if orig == formatted {
+            continue;
+        }
+        if opts.check {
+            eprintln!("crulz: {}: not formatted", i.display());
+            success = false;
+        } else if let Err(e) = std::fs::write(i, formatted) {
+            eprintln!("crulz: ERROR: {}: unable to write file: {}", i.display(), e);
+            success = false;
+        }
+    }
+
+    if !success {
+        std::process::exit(1);
+    }
+}
+
+fn highlight_main() {
+    let opts: HighlightOptions = parse_subcmd_args("highlight");
+    let pars_opts = opts.parser_opts(false);
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut success = true;
+    for i in &opts.inputs {
+        let input = match std::fs::read(i) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("crulz: ERROR: {}: unable to read file: {}", i.display(), e);
+                success = false;
+                continue;
+            }
+        };
+        let rendered = if opts.html {
+            highlight::to_html(&input, &pars_opts)
+        } else {
+            highlight::to_ansi(&input, &pars_opts)
+        };
+        stdout
+            .write_all(rendered.as_bytes())
+            .expect("unable to write result");
+    }
+    stdout.flush().expect("unable to flush result");
+
+    if !success {
+        std::process::exit(1);
+    }
 }
 
 fn main() {
     use crulz::ast::Mangle as _;
 
+    match std::env::args().nth(1).as_deref() {
+        Some("fmt") => {
+            fmt_main();
+            return;
+        }
+        Some("highlight") => {
+            highlight_main();
+            return;
+        }
+        _ => {}
+    }
+
     let opts = CrulzOptions::parse_args_default_or_exit();
     let vblvl = opts.verbose;
 
-    if opts.inputs.len() != 1 {
-        eprintln!(
-            "crulz: ERROR: expected exactly one input file, got {}",
-            opts.inputs.len()
-        );
+    if opts.inputs.is_empty() {
+        eprintln!("crulz: ERROR: expected at least one input file");
+        std::process::exit(1);
+    }
+    let batch = opts.inputs.len() > 1;
+    if batch
+        && (opts.trace
+            || opts.trace_format.is_some()
+            || opts.trace_output.is_some()
+            || !opts.breakpoints.is_empty())
+    {
+        eprintln!("crulz: ERROR: tracing is only supported with a single input file");
         std::process::exit(1);
     }
 
     let input_file = opts.inputs[0].to_owned();
-    let pars_opts = parser::Options {
-        escc: opts.escc.unwrap_or(b'\\'),
-        pass_escc: opts.pass_escc,
-    };
-
-    let mut trs = timing_of!(
-        opts.timings,
-        parser::file2ast,
-        parser::file2ast(Path::new(&input_file), pars_opts).expect("failed to parse input file")
-    );
-
-    if vblvl > 1 {
-        print_ast("AST before evaluation", &trs);
-    }
+    let pars_opts = opts.parser_opts(opts.pass_escc);
 
     #[allow(unused_assignments, unused_mut)]
     let mut comp_map = HashMap::<PathBuf, PathBuf>::new();
@@ -115,13 +566,13 @@ fn main() {
     cfg_if::cfg_if! {
         if #[cfg(feature = "compile")] {
             comp_map = opts.map_to_compilate
-                .into_iter()
+                .iter()
                 .map(|y| {
                     let tmp: Vec<_> = y.split('=').take(2).collect();
                     (PathBuf::from(tmp[0]), PathBuf::from(tmp[1]))
                 })
                 .collect();
-            comp_out = opts.compile_output.as_ref().map(|x| Path::new(x));
+            comp_out = opts.compile_output.as_deref();
         }
     };
 
@@ -133,11 +584,135 @@ fn main() {
             .collect(),
     );
 
-    timing_of!(
-        opts.timings,
-        interp::eval,
-        interp::eval(&mut trs, &mut ectx, comp_out,)
-    );
+    ectx.limits.max_steps = opts.max_steps;
+    if let Some(x) = opts.max_depth {
+        ectx.limits.max_depth = if x == 0 { None } else { Some(x) };
+    }
+    ectx.limits.max_output = opts.max_output;
+    if !opts.sandbox_root.is_empty() {
+        ectx.sandbox = interp::Sandbox::AllowRoots(opts.sandbox_root.clone());
+    } else if opts.sandbox {
+        ectx.sandbox = interp::Sandbox::DenyAll;
+    }
+
+    if opts.profile || opts.profile_folded.is_some() {
+        ectx.profile = Some(profile::Profile::new());
+    }
+
+    if batch {
+        if comp_out.is_some() {
+            eprintln!("crulz: ERROR: a compilate can only be written for a single input file");
+            std::process::exit(1);
+        }
+        let inputs: Vec<_> = opts.inputs.iter().map(PathBuf::from).collect();
+        let mut out = make_output(opts.output.as_deref(), opts.quiet);
+        let success = timing_of!(
+            opts.timings,
+            batch::eval_files,
+            eval_batch(
+                &inputs,
+                opts.jobs.unwrap_or(1),
+                &mut ectx,
+                &mut out.tee,
+                vblvl
+            )
+        );
+        out.finish(success);
+        if let Some(prof) = &ectx.profile {
+            write_profile(prof, opts.profile, opts.profile_folded.as_deref());
+        }
+        if !success {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let input = match ectx.loader.load(Path::new(&input_file)) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("crulz: ERROR: unable to read file '{}': {}", input_file, e);
+            std::process::exit(1);
+        }
+    };
+    let tracer = make_tracer(&opts, &input_file, &input, &ectx.opts);
+    // the tracer needs the source locations of the top-level nodes
+    let mut spanned = None;
+    let mut trs = if tracer.is_some() {
+        let doc = timing_of!(
+            opts.timings,
+            parser::incremental::Document::parse,
+            parser::incremental::Document::parse(input, ectx.opts.clone())
+        );
+        doc.report_errors(Path::new(&input_file))
+            .expect("failed to parse input file");
+        let nodes: Vec<_> = doc
+            .segments()
+            .iter()
+            .filter_map(|i| Some((i.span.start, i.result.clone().ok()??)))
+            .collect();
+        if vblvl > 1 {
+            let trs: Vec<_> = nodes.iter().map(|i| i.1.clone()).collect();
+            print_ast("AST before evaluation", &trs);
+        }
+        spanned = Some(nodes);
+        Vec::new()
+    } else {
+        timing_of!(
+            opts.timings,
+            parser::bytes2ast,
+            parser::bytes2ast(Path::new(&input_file), &input, &ectx.opts)
+                .expect("failed to parse input file")
+        )
+    };
+
+    if vblvl > 1 && spanned.is_none() {
+        print_ast("AST before evaluation", &trs);
+    }
+
+    // the result is only needed as a whole for the compilate and the AST dump
+    let streaming = comp_out.is_none() && tracer.is_none() && vblvl == 0;
+
+    let res = if let (Some(tracer), Some(nodes)) = (tracer, spanned) {
+        ectx.tracer = Some(tracer);
+        timing_of!(
+            opts.timings,
+            interp::eval_spanned,
+            interp::eval_spanned(nodes, &mut ectx, comp_out)
+        )
+        .map(|x| trs = x)
+    } else if streaming {
+        let mut out = make_output(opts.output.as_deref(), opts.quiet);
+        let res = timing_of!(
+            opts.timings,
+            interp::eval_streaming,
+            interp::eval_streaming(std::mem::take(&mut trs), &mut ectx, &mut out.tee)
+        );
+        out.finish(res.is_ok());
+        res
+    } else {
+        timing_of!(
+            opts.timings,
+            interp::eval,
+            interp::eval(&mut trs, &mut ectx, comp_out,)
+        )
+    };
+    if let Some(mut tracer) = ectx.tracer.take() {
+        if let Err(e) = tracer.finish() {
+            eprintln!("crulz: ERROR: unable to write trace: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(prof) = &ectx.profile {
+        write_profile(prof, opts.profile, opts.profile_folded.as_deref());
+    }
+    if let Err(e) = res {
+        eprintln!("crulz: ERROR: {}", e);
+        std::process::exit(1);
+    }
+
+    if streaming {
+        return;
+    }
 
     if vblvl > 0 {
         print_ast("AST after evaluation", &trs);
@@ -149,7 +724,7 @@ fn main() {
     }
 
     let mut blob = Vec::new();
-    trs.fmt(&mut blob, pars_opts.escc);
+    trs.fmt(&mut blob, &ectx.opts);
     let blob = &*blob;
 
     if let Some(x) = opts.output.as_ref() {
@@ -0,0 +1,320 @@
+//! partial evaluation of definition bodies, used before writing compfiles
+
+use crate::{
+    ast::{CmdEvalArgs, GroupType, Lift as _, Mangle, Node as ASTNode, VAN},
+    interp::{bind_arguments, is_evaluated, BuiltInFn, DefinesMap, ProcDefinesMap},
+};
+use atoi::atoi;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+/// the automatic built-ins without side effects
+const PURE_BUILTINS: &[&[u8]] = &[b"add", b"pass", b"une", b"unee"];
+
+/// the built-ins which change the definition named by their first argument
+const DEFINING_BUILTINS: &[&[u8]] = &[b"def", b"def-lazy", b"def-memo", b"undef"];
+
+/// optimises the bodies of the definitions: calls to non-recursive definitions
+/// and to the built-ins `add`, `pass`, `une` and `unee` are replaced by their
+/// expansion, and the results are simplified
+///
+/// Calls are only expanded if neither their arguments nor the expansion contain
+/// any arguments (e.g. `$0`), because these would be substituted at another time
+/// than during the normal evaluation. Definitions which are (re)defined or
+/// undefined by any body or by the remaining `content` aren't inlined, and if the
+/// name of such a call isn't a constant, nothing is inlined at all.
+pub fn optimize_defs(defs: &mut DefinesMap, procdefs: &ProcDefinesMap, content: &[ASTNode]) {
+    let mut optimizer = Optimizer::new(defs, procdefs, content);
+    let bodies: Vec<_> = defs
+        .keys()
+        .map(|name| (name.clone(), optimizer.body(name)))
+        .collect();
+    for (name, body) in bodies {
+        if let Some(x) = defs.get_mut(&name) {
+            x.1 = body;
+        }
+    }
+}
+
+/// collects the names of all calls with a constant command name
+fn collect_calls<'a>(node: &'a ASTNode, calls: &mut HashSet<&'a [u8]>) {
+    match node {
+        ASTNode::CmdEval { cmd, args } => {
+            if let [ASTNode::Constant { data, .. }] = &cmd[..] {
+                calls.insert(&data[..]);
+            }
+            for i in cmd.iter().chain(args.iter()) {
+                collect_calls(i, calls);
+            }
+        }
+        ASTNode::Grouped { elems, .. } => {
+            for i in elems {
+                collect_calls(i, calls);
+            }
+        }
+        ASTNode::Lambda { body, .. } => collect_calls(body, calls),
+        _ => {}
+    }
+}
+
+/// collects the names changed by calls to [`DEFINING_BUILTINS`],
+/// returns `false` if any of these names isn't a constant
+fn collect_redefined<'a>(node: &'a ASTNode, names: &mut HashSet<&'a [u8]>) -> bool {
+    match node {
+        ASTNode::CmdEval { cmd, args } => {
+            if let [ASTNode::Constant { data, .. }] = &cmd[..] {
+                if DEFINING_BUILTINS.contains(&&data[..]) {
+                    match args.0.first().and_then(ASTNode::as_constant) {
+                        Some(x) => {
+                            names.insert(x);
+                        }
+                        None => return false,
+                    }
+                }
+            }
+            cmd.iter()
+                .chain(args.iter())
+                .all(|i| collect_redefined(i, names))
+        }
+        ASTNode::Grouped { elems, .. } => elems.iter().all(|i| collect_redefined(i, names)),
+        ASTNode::Lambda { body, .. } => collect_redefined(body, names),
+        _ => true,
+    }
+}
+
+fn contains_argument(node: &ASTNode) -> bool {
+    match node {
+        ASTNode::Argument { .. } | ASTNode::NamedArgument { .. } | ASTNode::Arguments { .. } => {
+            true
+        }
+        ASTNode::CmdEval { cmd, args } => cmd.iter().chain(args.iter()).any(contains_argument),
+        ASTNode::Grouped { elems, .. } => elems.iter().any(contains_argument),
+        ASTNode::Lambda { body, .. } => contains_argument(body),
+        _ => false,
+    }
+}
+
+struct Optimizer<'a> {
+    defs: &'a DefinesMap,
+    procdefs: &'a ProcDefinesMap,
+    /// the definitions which don't call themselves (directly or indirectly)
+    /// and aren't changed by any body or the content
+    inlinable: HashSet<&'a [u8]>,
+    /// the already optimised bodies
+    done: HashMap<&'a [u8], Arc<ASTNode>>,
+}
+
+impl<'a> Optimizer<'a> {
+    fn new(defs: &'a DefinesMap, procdefs: &'a ProcDefinesMap, content: &'a [ASTNode]) -> Self {
+        let calls: HashMap<&[u8], HashSet<&[u8]>> = defs
+            .iter()
+            .map(|(name, (_, body))| {
+                let mut calls = HashSet::new();
+                collect_calls(body, &mut calls);
+                (&name[..], calls)
+            })
+            .collect();
+        let reaches_itself = |name: &[u8]| {
+            let mut seen = HashSet::new();
+            let mut todo: Vec<_> = calls[name].iter().copied().collect();
+            while let Some(i) = todo.pop() {
+                if i == name {
+                    return true;
+                } else if seen.insert(i) {
+                    todo.extend(calls.get(i).into_iter().flatten().copied());
+                }
+            }
+            false
+        };
+        let mut redefined = HashSet::new();
+        let inlinable = if defs
+            .values()
+            .map(|(_, body)| &**body)
+            .chain(content)
+            .all(|i| collect_redefined(i, &mut redefined))
+        {
+            calls
+                .keys()
+                .copied()
+                .filter(|i| !redefined.contains(i) && !reaches_itself(i))
+                .collect()
+        } else {
+            HashSet::new()
+        };
+        Self {
+            defs,
+            procdefs,
+            inlinable,
+            done: HashMap::new(),
+        }
+    }
+
+    fn body(&mut self, name: &'a [u8]) -> Arc<ASTNode> {
+        if let Some(x) = self.done.get(name) {
+            return x.clone();
+        }
+        let mut body = ASTNode::clone(&self.defs[name].1);
+        self.fold(&mut body);
+        let body = Arc::new(body.simplify());
+        self.done.insert(name, body.clone());
+        body
+    }
+
+    fn fold(&mut self, node: &mut ASTNode) {
+        match node {
+            ASTNode::CmdEval { cmd, args } => {
+                for i in cmd.iter_mut().chain(args.iter_mut()) {
+                    self.fold(i);
+                }
+                if let Some(x) = self.expand(cmd, args) {
+                    *node = x;
+                }
+            }
+            ASTNode::Grouped { elems, .. } => {
+                for i in elems {
+                    self.fold(i);
+                }
+            }
+            ASTNode::Lambda { body, .. } => self.fold(Arc::make_mut(body)),
+            _ => {}
+        }
+    }
+
+    /// mirrors [`eval_cmd`](crate::interp) for calls with already evaluated arguments
+    fn expand(&mut self, cmd: &[ASTNode], args: &CmdEvalArgs) -> Option<ASTNode> {
+        let name = match cmd {
+            [ASTNode::Constant {
+                non_space: true,
+                data,
+            }] => &data[..],
+            _ => return None,
+        };
+        if !args
+            .iter()
+            .all(|i| is_evaluated(i) && !contains_argument(i))
+        {
+            return None;
+        }
+        // dissolving groups are spliced into the argument list, like in `eval_args`
+        let args = CmdEvalArgs(
+            args.iter()
+                .cloned()
+                .flat_map(|i| match i {
+                    ASTNode::Grouped {
+                        typ: GroupType::Dissolving,
+                        elems,
+                    } => elems,
+                    _ => i.lift_ast(),
+                })
+                .collect::<VAN>(),
+        );
+
+        let ret = match self.procdefs.get(name).copied() {
+            Some((argc, BuiltInFn::Automatic(f))) if PURE_BUILTINS.contains(&name) => {
+                if argc.is_some_and(|n| n != args.len())
+                    || (name == b"add"
+                        && !args
+                            .iter()
+                            .all(|i| i.as_constant().and_then(atoi::<i64>).is_some()))
+                {
+                    return None;
+                }
+                f(&args.0)?
+            }
+            // built-ins take precedence over definitions
+            Some(_) => return None,
+            None => {
+                let (name, (argc, _)) = self.defs.get_key_value(name)?;
+                let args = match &argc.params {
+                    Some(params) => bind_arguments(params, &args).ok()?,
+                    None => args,
+                };
+                if !self.inlinable.contains(&name[..]) || !argc.accepts(args.len()) {
+                    return None;
+                }
+                let mut ret = self
+                    .body(name)
+                    .apply_call_arguments(&args.0, argc.fixed, argc.params())
+                    .ok()?;
+                self.fold(&mut ret);
+                ret
+            }
+        };
+        if contains_argument(&ret) {
+            None
+        } else {
+            Some(ret)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{interp::eval_with, parser::parse_toplevel};
+
+    fn optimized(input: &str) -> Vec<(String, String)> {
+        let (out, mut ctx) = eval_with(input, |_| {}).unwrap();
+        // the leftover calls of the output are the content of the compfile
+        let content = parse_toplevel(&out, &ctx.opts).unwrap();
+        optimize_defs(&mut ctx.defs, &ctx.procdefs, &content);
+        let mut ret: Vec<_> = ctx
+            .defs
+            .iter()
+            .map(|(name, (_, body))| {
+                let mut out = Vec::new();
+                body.fmt(&mut out, &ctx.opts);
+                (
+                    String::from_utf8(name.clone()).unwrap(),
+                    String::from_utf8(out).unwrap(),
+                )
+            })
+            .collect();
+        ret.sort();
+        ret
+    }
+
+    #[test]
+    fn test_optimize_defs() {
+        let defs = optimized(
+            "\\def-lazy(a 0 \\(add 1 \\(add 2 3)))\
+             \\def-lazy(b 1 <$0 \\(pass \\(a))>)\
+             \\def-lazy(c 1 \\(b \\(a)) \\(b $0) \\(add $0 1) \\(undef a))\
+             \\def-lazy(r 1 \\(r $0) \\(c x))\
+             \\def-lazy(n (x y=2) \\(add $x $y))\\def-lazy(m 0 \\(n y=3 1)\\(n 1))",
+        );
+        assert_eq!(
+            defs,
+            [
+                ("a".to_string(), "6".to_string()),
+                // `a` is undefined by `c`, thus it isn't inlined
+                ("b".to_string(), "<$0\\(pass \\(a))>".to_string()),
+                (
+                    "c".to_string(),
+                    "\\(b \\(a))\\(b $0)\\(add $0 1)\\(undef a)".to_string()
+                ),
+                // keyword arguments and defaults are bound before inlining
+                ("m".to_string(), "43".to_string()),
+                ("n".to_string(), "\\(add $x $y)".to_string()),
+                // recursive definitions aren't inlined, but can inline other ones
+                // the arguments of def-lazy are whitespace-delimited
+                (
+                    "r".to_string(),
+                    "\\(r $0)\\(b \\(a))<x\\(pass \\(a))>\\(add x 1)\\(undef a)".to_string()
+                ),
+            ]
+        );
+
+        // any definition might be changed by a definition with a computed name
+        let defs =
+            optimized("\\def-lazy(a 0 x)\\def-lazy(b 0 \\(a))\\def-lazy(d 1 \\(def $0 0 y))");
+        assert_eq!(defs[1], ("b".to_string(), "\\(a)".to_string()));
+        // the content is checked too, as it is evaluated after loading the compfile
+        let defs = optimized("\\def-lazy(a 0 x)\\def-lazy(b 0 \\(a))\\(unknown \\(undef a))");
+        assert_eq!(defs[1], ("b".to_string(), "\\(a)".to_string()));
+        let defs = optimized("\\def-lazy(a 0 x)\\def-lazy(b 0 \\(a))");
+        assert_eq!(defs[1], ("b".to_string(), "x".to_string()));
+    }
+}
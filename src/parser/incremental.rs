@@ -0,0 +1,235 @@
+//! incremental re-parsing, e.g. for editor integration
+//!
+//! The top level of a document is a sequence of independent segments (see
+//! `parse_toplevel_segment`), and the parse result of each segment only depends
+//! on the text starting at the segment. Thus, after an edit, only the segments
+//! which looked at the edited region need to be re-parsed, and as soon as the
+//! re-parsing arrives at the (shifted) start of an old segment behind the edited
+//! region, all remaining segments can be reused as-is.
+
+use super::{
+    get_offset_of, parse_toplevel_segment, report_errors, AstBuilder, Error, ErrorDetail, Options,
+};
+use crate::ast::{Node as ASTNode, VAN};
+use std::{marker::PhantomData, ops::Range, path::Path};
+
+/// an owned version of [`Error`], with byte offsets into the source
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyntaxError {
+    pub origin: usize,
+    pub offending: Range<usize>,
+    pub opening: Option<Range<usize>>,
+    pub detail: ErrorDetail,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub span: Range<usize>,
+    pub result: Result<Option<ASTNode>, SyntaxError>,
+
+    /// end of the part of the source which was looked at while parsing this segment,
+    /// `usize::MAX` if this segment depends on the end of the source
+    extent: usize,
+}
+
+/// a parsed document, which supports incremental re-parsing after edits
+#[derive(Clone, Debug)]
+pub struct Document {
+    opts: Options,
+    source: Vec<u8>,
+    segments: Vec<Segment>,
+}
+
+#[cfg(test)]
+thread_local! {
+    /// count of the segments parsed by [`Document::parse_from`]
+    pub(super) static PARSED_SEGMENTS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+fn span_of(source: &[u8], x: &[u8]) -> Range<usize> {
+    let start = get_offset_of(source, x);
+    start..(start + x.len())
+}
+
+fn shift(x: &mut Range<usize>, delta: isize) {
+    x.start = (x.start as isize + delta) as usize;
+    x.end = (x.end as isize + delta) as usize;
+}
+
+impl SyntaxError {
+    /// a suggestion how to fix this error, see [`Error::help`](super::Error::help)
+    pub fn help(&self, source: &[u8], opts: &Options) -> Option<String> {
+        let offending = source.get(self.offending.clone())?.first().copied();
+        self.detail.help(offending, opts)
+    }
+
+    fn to_error<'a>(&self, source: &'a [u8]) -> Error<'a> {
+        Error {
+            origin: &source[self.origin..],
+            offending: &source[self.offending.clone()],
+            opening: self.opening.clone().map(|x| &source[x]),
+            detail: self.detail.clone(),
+            _non_exhaustive: PhantomData,
+        }
+    }
+}
+
+impl Segment {
+    fn shift(&mut self, delta: isize) {
+        shift(&mut self.span, delta);
+        if self.extent != usize::MAX {
+            self.extent = (self.extent as isize + delta) as usize;
+        }
+        if let Err(e) = &mut self.result {
+            e.origin = (e.origin as isize + delta) as usize;
+            shift(&mut e.offending, delta);
+            if let Some(x) = &mut e.opening {
+                shift(x, delta);
+            }
+        }
+    }
+}
+
+impl Document {
+    pub fn parse(source: Vec<u8>, opts: Options) -> Self {
+        let mut ret = Self {
+            opts,
+            source,
+            segments: Vec::new(),
+        };
+        ret.segments = ret.parse_from(0, |_| None);
+        ret
+    }
+
+    #[inline]
+    pub fn source(&self) -> &[u8] {
+        &self.source
+    }
+
+    #[inline]
+    pub fn opts(&self) -> &Options {
+        &self.opts
+    }
+
+    #[inline]
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// the top-level nodes, like they would be returned by
+    /// [`parse_toplevel_recovering`](super::parse_toplevel_recovering)
+    pub fn nodes(&self) -> impl Iterator<Item = &ASTNode> {
+        self.segments
+            .iter()
+            .filter_map(|i| i.result.as_ref().ok().and_then(Option::as_ref))
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &SyntaxError> {
+        self.segments.iter().filter_map(|i| i.result.as_ref().err())
+    }
+
+    /// prints diagnostics for all syntax errors to stderr,
+    /// like [`bytes2ast`](super::bytes2ast), fails if there are any
+    pub fn report_errors(&self, filename: &Path) -> Result<(), anyhow::Error> {
+        let errs: Vec<_> = self.errors().map(|e| e.to_error(&self.source)).collect();
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(report_errors(filename, &self.source, &self.opts, &errs))
+        }
+    }
+
+    /// converts this document into the top-level AST, dropping any errors
+    pub fn into_ast(self) -> VAN {
+        self.segments
+            .into_iter()
+            .filter_map(|i| i.result.ok().flatten())
+            .collect()
+    }
+
+    /// parses segments starting at `pos` until the end of the source,
+    /// or until `reuse` returns the remaining segments for the current position
+    fn parse_from(
+        &self,
+        mut pos: usize,
+        mut reuse: impl FnMut(usize) -> Option<Vec<Segment>>,
+    ) -> Vec<Segment> {
+        let source = &self.source[..];
+        let mut ret = Vec::new();
+        while pos < source.len() {
+            if let Some(rest) = reuse(pos) {
+                ret.extend(rest);
+                break;
+            }
+            #[cfg(test)]
+            PARSED_SEGMENTS.with(|x| x.set(x.get() + 1));
+            let data = &source[pos..];
+            let (rest, res) = parse_toplevel_segment(data, &self.opts, &AstBuilder);
+            let end = get_offset_of(source, rest);
+            // the parser looks behind the end of a segment for the escape sequence
+            // which ends a constant, and for the line break after the end tag of a
+            // verbatim block (`\r\n`)
+            let lookahead = std::cmp::max(self.opts.escc.len(), 2);
+            let extent = match res {
+                Ok(_) if end + lookahead <= source.len() => end + lookahead,
+                _ => usize::MAX,
+            };
+            ret.push(Segment {
+                span: pos..end,
+                result: res.map_err(|e| SyntaxError {
+                    origin: get_offset_of(source, e.origin),
+                    offending: span_of(source, e.offending),
+                    opening: e.opening.map(|x| span_of(source, x)),
+                    detail: e.detail,
+                }),
+                extent,
+            });
+            pos = end;
+        }
+        ret
+    }
+
+    /// replaces the given byte range of the source with `replacement`
+    /// and re-parses the affected segments
+    ///
+    /// # Panics
+    /// if `range` is out of bounds
+    pub fn edit(&mut self, range: Range<usize>, replacement: &[u8]) {
+        assert!(range.start <= range.end && range.end <= self.source.len());
+        let delta = replacement.len() as isize - range.len() as isize;
+
+        // the first segment which looked at the edited region
+        let first = self
+            .segments
+            .iter()
+            .position(|i| i.extent > range.start)
+            .unwrap_or(self.segments.len());
+        let mut tail = self.segments.split_off(first).into_iter().peekable();
+        let restart = tail.peek().map_or(self.source.len(), |i| i.span.start);
+
+        self.source
+            .splice(range.clone(), replacement.iter().copied());
+
+        let reparsed = self.parse_from(restart, |pos| {
+            // skip segments which are invalidated or already covered
+            while let Some(i) = tail.peek() {
+                if i.span.start >= range.end && (i.span.start as isize + delta) as usize >= pos {
+                    break;
+                }
+                tail.next();
+            }
+            match tail.peek() {
+                Some(i) if (i.span.start as isize + delta) as usize == pos => Some(
+                    tail.by_ref()
+                        .map(|mut i| {
+                            i.shift(delta);
+                            i
+                        })
+                        .collect(),
+                ),
+                _ => None,
+            }
+        });
+        self.segments.extend(reparsed);
+    }
+}
@@ -0,0 +1,915 @@
+use crate::ast::{ArgumentsForm, CmdEvalArgs, GroupType, Node as ASTNode, VAN};
+use bstr::ByteSlice;
+use itertools::Itertools as _;
+use std::marker::PhantomData;
+
+pub mod incremental;
+mod tests;
+pub mod tokens;
+
+// === parser options
+
+/// begin and end marker of a group
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ScopeMarkers {
+    pub begin: u8,
+    pub end: u8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    /// escape sequence, default: `\`
+    pub escc: Vec<u8>,
+    pub pass_escc: bool,
+    /// markers of strict groups and command evaluations, default: `(` `)`
+    pub strict_markers: ScopeMarkers,
+    /// markers of loose groups, default: `{` `}`
+    pub loose_markers: ScopeMarkers,
+    /// prefix of arguments, default: `$`
+    pub arg_sigil: u8,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            escc: b"\\".to_vec(),
+            pass_escc: false,
+            strict_markers: ScopeMarkers {
+                begin: b'(',
+                end: b')',
+            },
+            loose_markers: ScopeMarkers {
+                begin: b'{',
+                end: b'}',
+            },
+            arg_sigil: b'$',
+        }
+    }
+}
+
+impl Options {
+    /// checks that all control characters are distinct and not white-space,
+    /// and that the escape sequence doesn't contain any other control character
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let ctrl = [
+            self.strict_markers.begin,
+            self.strict_markers.end,
+            self.loose_markers.begin,
+            self.loose_markers.end,
+            self.arg_sigil,
+        ];
+        if self.escc.is_empty() {
+            Err("escape sequence must not be empty")
+        } else if ctrl
+            .iter()
+            .chain(self.escc.iter())
+            .any(u8::is_ascii_whitespace)
+        {
+            Err("control characters must not be white-space")
+        } else if ctrl.iter().unique().count() != ctrl.len()
+            || self.escc.iter().any(|i| ctrl.contains(i))
+        {
+            Err("control characters must be distinct")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// if `x` is a begin-of-scope marker, returns the corresponding
+    /// end-of-scope marker and the group type
+    fn scope_begin(&self, x: u8) -> Option<(u8, GroupType)> {
+        if x == self.strict_markers.begin {
+            Some((self.strict_markers.end, GroupType::Strict))
+        } else if x == self.loose_markers.begin {
+            Some((self.loose_markers.end, GroupType::Loose))
+        } else {
+            None
+        }
+    }
+
+    /// if `x` is an end-of-scope marker, returns the corresponding begin-of-scope marker
+    fn scope_begin_of(&self, x: u8) -> Option<u8> {
+        [self.strict_markers, self.loose_markers]
+            .iter()
+            .find(|i| i.end == x)
+            .map(|i| i.begin)
+    }
+
+    fn is_scope_end(&self, x: u8) -> bool {
+        x == self.strict_markers.end || x == self.loose_markers.end
+    }
+
+    /// single-byte control characters, the escape sequence is handled separately
+    fn is_ctrl(&self, x: u8) -> bool {
+        x == self.arg_sigil || self.is_scope_end(x) || self.scope_begin(x).is_some()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum ErrorDetail {
+    #[error("unexpected EOF")]
+    UnexpectedEof,
+    #[error("got empty eval statement")]
+    EmptyEval,
+    #[error("escape sequence followed by white-space")]
+    InvalidEval,
+    #[error("invalid verbatim block header")]
+    InvalidVerbatim,
+    #[error("expected '{}' instead", char::from(*.0))]
+    ExpectedInstead(u8),
+
+    /// Escaped end-of-scope markers are dangerous, because they probably don't
+    /// do, what you would naively expect. The correct way to escape them is
+    /// to *not* escape the corresponding begin-of-scope marker, which thus
+    /// constitutes a Group.
+    #[error("dangerous escaped end-of-scope marker ('{}')", char::from(*.0))]
+    DangerousEos(u8),
+    #[error("unexpected unbalanced end-of-scope marker ('{}')", char::from(*.0))]
+    UnbalancedEos(u8),
+}
+
+#[allow(clippy::upper_case_acronyms)]
+type PED = ErrorDetail;
+
+/// the rest after the parsed object and the object itself
+type ParseResult<'a, T> = Result<(&'a [u8], T), Error<'a>>;
+
+#[derive(Debug)]
+pub struct Error<'a> {
+    pub origin: &'a [u8],
+    pub offending: &'a [u8],
+    /// the opening delimiter of the unclosed scope, if any
+    pub opening: Option<&'a [u8]>,
+    pub detail: PED,
+    _non_exhaustive: PhantomData<()>,
+}
+
+impl Error<'_> {
+    /// a short description of the primary (offending) location
+    fn primary_message(&self) -> Option<String> {
+        match self.detail {
+            PED::ExpectedInstead(c) => Some(if self.offending.is_empty() {
+                "unexpected end of file".to_string()
+            } else {
+                format!("expected '{}' before this", char::from(c))
+            }),
+            _ => None,
+        }
+    }
+
+    /// a suggestion how to fix this error
+    pub fn help(&self, opts: &Options) -> Option<String> {
+        self.detail.help(self.offending.first().copied(), opts)
+    }
+}
+
+impl ErrorDetail {
+    /// a suggestion how to fix this error, `offending` is the first
+    /// byte of the offending part, if any
+    fn help(&self, offending: Option<u8>, opts: &Options) -> Option<String> {
+        let escc = String::from_utf8_lossy(&opts.escc);
+        Some(match *self {
+            PED::ExpectedInstead(c) => match offending {
+                Some(x) if x != c && opts.is_scope_end(x) => format!(
+                    "'{}' doesn't match the opening delimiter, \
+                     remove the stray '{}' or insert the missing '{}' before it",
+                    char::from(x),
+                    char::from(x),
+                    char::from(c)
+                ),
+                _ => format!(
+                    "insert the missing '{}' to close the opening delimiter",
+                    char::from(c)
+                ),
+            },
+            PED::DangerousEos(x) => {
+                let begin = char::from(opts.scope_begin_of(x)?);
+                format!(
+                    "write '{e}' instead of '{c}{e}', a literal '{b}...{e}' is \
+                     written as an unescaped group",
+                    b = begin,
+                    c = escc,
+                    e = char::from(x)
+                )
+            }
+            PED::UnbalancedEos(x) => format!(
+                "remove the '{}' or insert the matching '{}' before it",
+                char::from(x),
+                char::from(opts.scope_begin_of(x)?)
+            ),
+            PED::EmptyEval => format!(
+                "an eval statement needs a command name, e.g. '{}{}name args{}'",
+                escc,
+                char::from(opts.strict_markers.begin),
+                char::from(opts.strict_markers.end)
+            ),
+            PED::InvalidEval => format!(
+                "to get a literal escape sequence, escape it: '{}{}'",
+                escc, escc
+            ),
+            PED::InvalidVerbatim => format!(
+                "verbatim blocks start with '{}verbatim<<TAG' followed by a newline",
+                escc
+            ),
+            PED::UnexpectedEof => return None,
+        })
+    }
+}
+
+// === parse trait
+
+pub(crate) trait Parse: Sized {
+    /// # Return value
+    /// * `Ok(rest, parsed_obj)`
+    /// * `Err(offending_code, description)`
+    fn parse<'a>(data: &'a [u8], opts: &Options) -> Result<(&'a [u8], Self), Error<'a>>;
+}
+
+/// constructs the nodes of the parsed AST, which allows the parser to produce
+/// [`ast::Node`](crate::ast::Node)s as well as the nodes of the experimental
+/// arena AST (`ast::arena`, only built for the tests and benchmarks)
+pub trait Builder {
+    type Node;
+
+    fn null(&self) -> Self::Node;
+
+    /// a constant which is a part of the parsed input
+    fn constant(&self, non_space: bool, data: &[u8]) -> Self::Node;
+
+    /// a constant which isn't a part of the parsed input
+    fn owned_constant(&self, non_space: bool, data: Vec<u8>) -> Self::Node;
+
+    fn argument(&self, indirection: usize, index: Option<usize>) -> Self::Node;
+
+    fn named_argument(&self, indirection: usize, name: &[u8]) -> Self::Node;
+
+    fn arguments(&self, indirection: usize, form: ArgumentsForm) -> Self::Node;
+
+    fn grouped(&self, typ: GroupType, elems: Vec<Self::Node>) -> Self::Node;
+
+    /// `args` are white-space delimited
+    fn cmd_eval(&self, cmd: Vec<Self::Node>, args: Vec<Self::Node>) -> Self::Node;
+
+    fn is_space(&self, node: &Self::Node) -> bool;
+}
+
+/// the builder of [`ast::Node`](crate::ast::Node)s
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AstBuilder;
+
+impl Builder for AstBuilder {
+    type Node = ASTNode;
+
+    #[inline]
+    fn null(&self) -> ASTNode {
+        ASTNode::NullNode
+    }
+
+    #[inline]
+    fn constant(&self, non_space: bool, data: &[u8]) -> ASTNode {
+        ASTNode::Constant {
+            non_space,
+            data: data.into(),
+        }
+    }
+
+    #[inline]
+    fn owned_constant(&self, non_space: bool, data: Vec<u8>) -> ASTNode {
+        ASTNode::Constant {
+            non_space,
+            data: data.into(),
+        }
+    }
+
+    #[inline]
+    fn argument(&self, indirection: usize, index: Option<usize>) -> ASTNode {
+        ASTNode::Argument { indirection, index }
+    }
+
+    #[inline]
+    fn named_argument(&self, indirection: usize, name: &[u8]) -> ASTNode {
+        ASTNode::NamedArgument {
+            indirection,
+            name: name.into(),
+        }
+    }
+
+    #[inline]
+    fn arguments(&self, indirection: usize, form: ArgumentsForm) -> ASTNode {
+        ASTNode::Arguments { indirection, form }
+    }
+
+    #[inline]
+    fn grouped(&self, typ: GroupType, elems: VAN) -> ASTNode {
+        ASTNode::Grouped { typ, elems }
+    }
+
+    #[inline]
+    fn cmd_eval(&self, cmd: VAN, args: VAN) -> ASTNode {
+        ASTNode::CmdEval {
+            cmd,
+            args: CmdEvalArgs::from_wsdelim(args),
+        }
+    }
+
+    #[inline]
+    fn is_space(&self, node: &ASTNode) -> bool {
+        node.is_space()
+    }
+}
+
+// === parser utils
+
+fn get_offset_of<T>(whole_buffer: &T, part: &T) -> usize
+where
+    T: AsRef<[u8]> + ?Sized,
+{
+    // NOTE: use offset_from() once it's stable
+    part.as_ref().as_ptr() as usize - whole_buffer.as_ref().as_ptr() as usize
+}
+
+pub(crate) fn str_slice_between<'a>(whole_buffer_start: &'a [u8], post_part: &'a [u8]) -> &'a [u8] {
+    &whole_buffer_start[..get_offset_of(whole_buffer_start, post_part)]
+}
+
+/// 1. part while f(x) == true, then 2. part
+fn str_split_at_while(x: &[u8], f: impl FnMut(&u8) -> bool) -> (&[u8], &[u8]) {
+    x.split_at(x.bytes().take_while(f).count())
+}
+
+/// splits off a parameter name (e.g. `href` of `$href`), which consists of
+/// ASCII letters, digits and underscores, but doesn't start with a digit
+pub(crate) fn str_split_at_param_name(x: &[u8]) -> (&[u8], &[u8]) {
+    if x.first().map_or(true, u8::is_ascii_digit) {
+        return x.split_at(0);
+    }
+    str_split_at_while(x, |&i| i.is_ascii_alphanumeric() || i == b'_')
+}
+
+/// escaped escape sequence or other escaped code: optional passthrough
+///
+/// `data` starts after the escape sequence
+fn parse_escaped_const<'a, B: Builder>(
+    data: &'a [u8],
+    opts: &Options,
+    b: &B,
+) -> Option<(&'a [u8], B::Node)> {
+    let (esc, rest) = if data.starts_with(&opts.escc) {
+        data.split_at(opts.escc.len())
+    } else {
+        let i = *data.first()?;
+        if i == b'\n' {
+            return Some((&data[1..], b.null()));
+        } else if i != opts.loose_markers.begin
+            && i != opts.loose_markers.end
+            && i != opts.arg_sigil
+        {
+            return None;
+        }
+        data.split_at(1)
+    };
+    if !opts.pass_escc {
+        return Some((rest, b.constant(true, esc)));
+    }
+    let mut ret = Vec::with_capacity(opts.escc.len() + esc.len());
+    ret.extend_from_slice(&opts.escc);
+    ret.extend_from_slice(esc);
+    Some((rest, b.owned_constant(true, ret)))
+}
+
+fn str_split_at_ctrl<'a>(
+    data: &'a [u8],
+    opts: &Options,
+    f_do_cont_at: impl Fn(&u8) -> bool,
+) -> (&'a [u8], &'a [u8]) {
+    let pos = (0..data.len())
+        .find(|&i| {
+            let x = data[i];
+            opts.is_ctrl(x) || !f_do_cont_at(&x) || data[i..].starts_with(&opts.escc)
+        })
+        .unwrap_or(data.len());
+    data.split_at(pos)
+}
+
+/// if `data` starts with a comment, returns the rest after the comment
+///
+/// * line comments: `\%` until and including the end of the line
+/// * block comments: `\%*` until and including `*%`, these can't be nested
+pub(crate) fn skip_comment<'a>(
+    data: &'a [u8],
+    opts: &Options,
+) -> Result<Option<&'a [u8]>, Error<'a>> {
+    let rest = match data
+        .strip_prefix(&opts.escc[..])
+        .and_then(|x| x.strip_prefix(b"%"))
+    {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    Ok(Some(if let Some(x) = rest.strip_prefix(b"*") {
+        match x.find(b"*%") {
+            Some(pos) => &x[pos + 2..],
+            None => {
+                return Err(Error {
+                    origin: data,
+                    offending: str_slice_between(data, x),
+                    opening: None,
+                    detail: PED::UnexpectedEof,
+                    _non_exhaustive: PhantomData,
+                })
+            }
+        }
+    } else {
+        match rest.find_byte(b'\n') {
+            Some(pos) => &rest[pos + 1..],
+            None => &rest[rest.len()..],
+        }
+    }))
+}
+
+/// verbatim blocks: `\verbatim<<TAG`, followed by a newline and the content,
+/// which ends before the first line consisting only of `TAG`. The line break
+/// after the terminating `TAG` isn't part of the block.
+/// Nothing inside the content is interpreted.
+///
+/// `after` starts after the escape sequence
+fn parse_verbatim<'a, B: Builder>(
+    data: &'a [u8],
+    after: &'a [u8],
+    b: &B,
+) -> Option<ParseResult<'a, B::Node>> {
+    let header = after.strip_prefix(b"verbatim<<")?;
+    let (tag, rest) = str_split_at_while(header, |i| !i.is_ascii_whitespace());
+    let content = match rest
+        .strip_prefix(b"\n")
+        .or_else(|| rest.strip_prefix(b"\r\n"))
+    {
+        Some(x) if !tag.is_empty() => x,
+        _ => {
+            return Some(Err(Error {
+                origin: data,
+                offending: str_slice_between(data, rest),
+                opening: None,
+                detail: PED::InvalidVerbatim,
+                _non_exhaustive: PhantomData,
+            }))
+        }
+    };
+
+    let mut pos = 0;
+    Some(loop {
+        let line = &content[pos..];
+        if line.starts_with(tag)
+            && matches!(&line[tag.len()..], [] | [b'\n', ..] | [b'\r', b'\n', ..])
+        {
+            let (content, rest) = content.split_at(pos);
+            break Ok((
+                &rest[tag.len()..],
+                b.constant(!content.iter().all(u8::is_ascii_whitespace), content),
+            ));
+        }
+        match content[pos..].find_byte(b'\n') {
+            Some(x) => pos += x + 1,
+            None => {
+                break Err(Error {
+                    origin: data,
+                    offending: str_slice_between(data, content),
+                    opening: None,
+                    detail: PED::UnexpectedEof,
+                    _non_exhaustive: PhantomData,
+                })
+            }
+        }
+    })
+}
+
+/// `opening` is the opening delimiter of the scope which should be closed by `c`
+fn do_expect<'a>(
+    origin: &'a [u8],
+    opening: &'a [u8],
+    rest: &'a [u8],
+    c: u8,
+) -> Result<&'a [u8], Error<'a>> {
+    if rest.first() == Some(&c) {
+        Ok(&rest[1..])
+    } else {
+        Err(Error {
+            origin,
+            offending: &rest[..std::cmp::min(rest.len(), 1)],
+            opening: Some(opening),
+            detail: PED::ExpectedInstead(c),
+            _non_exhaustive: PhantomData,
+        })
+    }
+}
+
+impl Parse for ASTNode {
+    #[inline]
+    fn parse<'a>(data: &'a [u8], opts: &Options) -> Result<(&'a [u8], Self), Error<'a>> {
+        parse_node(data, opts, &AstBuilder)
+    }
+}
+
+impl Parse for VAN {
+    #[inline]
+    fn parse<'a>(data: &'a [u8], opts: &Options) -> Result<(&'a [u8], Self), Error<'a>> {
+        parse_nodes(data, opts, &AstBuilder)
+    }
+}
+
+fn parse_node<'a, B: Builder>(data: &'a [u8], opts: &Options, b: &B) -> ParseResult<'a, B::Node> {
+    let i = *data.first().ok_or(Error {
+        origin: data,
+        offending: data,
+        opening: None,
+        detail: PED::UnexpectedEof,
+        _non_exhaustive: PhantomData,
+    })?;
+    if let Some(after) = data.strip_prefix(&opts.escc[..]) {
+        let i = *after.first().ok_or(Error {
+            origin: data,
+            offending: data,
+            opening: None,
+            detail: PED::UnexpectedEof,
+            _non_exhaustive: PhantomData,
+        })?;
+        if i == opts.strict_markers.begin {
+            // got begin of cmdeval block
+            let (rest, mut vanx) = parse_nodes(&after[1..], opts, b)?;
+            if vanx.is_empty() {
+                return Err(Error {
+                    origin: data,
+                    offending: &data[..std::cmp::min(data.len(), opts.escc.len() + 2)],
+                    opening: None,
+                    detail: PED::EmptyEval,
+                    _non_exhaustive: PhantomData,
+                });
+            }
+            let rest = do_expect(
+                data,
+                &data[..opts.escc.len() + 1],
+                rest,
+                opts.strict_markers.end,
+            )?;
+
+            // extract command
+            assert!(!vanx.is_empty());
+            let split_point = vanx
+                .iter()
+                .enumerate()
+                .filter_map(|y| if b.is_space(y.1) { Some(y.0 + 1) } else { None })
+                .next()
+                .unwrap_or(1);
+            let van = vanx.split_off(split_point);
+            let mut cmd = vanx;
+            if b.is_space(cmd.last().unwrap()) {
+                cmd.pop();
+            }
+            Ok((rest, b.cmd_eval(cmd, van)))
+        } else if let Some(c) = parse_escaped_const(after, opts, b) {
+            Ok(c)
+        } else if let Some(x) = parse_verbatim(data, after, b) {
+            x
+        } else if opts.is_scope_end(i) {
+            Err(Error {
+                origin: data,
+                offending: str_slice_between(data, &after[1..]),
+                opening: None,
+                detail: PED::DangerousEos(i),
+                _non_exhaustive: PhantomData,
+            })
+        } else {
+            // interpret it as a command (LaTeX-alike)
+            let (cmd, mut rest) = str_split_at_ctrl(after, opts, |x| !x.is_ascii_whitespace());
+            if cmd.is_empty() {
+                return Err(Error {
+                    origin: data,
+                    offending: str_slice_between(data, &after[1..]),
+                    opening: None,
+                    detail: PED::InvalidEval,
+                    _non_exhaustive: PhantomData,
+                });
+            }
+            let args = if rest.first() == Some(&opts.strict_markers.begin) {
+                let (tmp_rest, van) = parse_nodes(&rest[1..], opts, b)?;
+                rest = do_expect(data, &rest[..1], tmp_rest, opts.strict_markers.end)?;
+                van
+            } else {
+                Vec::new()
+            };
+            Ok((rest, b.cmd_eval(vec![b.constant(true, cmd)], args)))
+        }
+    } else if i == opts.arg_sigil {
+        let (cdat, rest) = str_split_at_while(&data[1..], |&i| i == opts.arg_sigil);
+        if let Some(form) = rest.first().copied().and_then(ArgumentsForm::from_marker) {
+            return Ok((&rest[1..], b.arguments(cdat.len(), form)));
+        }
+        let (name, after) = str_split_at_param_name(rest);
+        if !name.is_empty() {
+            return Ok((after, b.named_argument(cdat.len(), name)));
+        }
+        let (idxs, rest) = str_split_at_while(rest, u8::is_ascii_digit);
+        Ok((rest, b.argument(cdat.len(), atoi::atoi(idxs))))
+    } else if opts.is_scope_end(i) {
+        Err(Error {
+            origin: data,
+            offending: &data[..1],
+            opening: None,
+            detail: PED::UnbalancedEos(i),
+            _non_exhaustive: PhantomData,
+        })
+    } else if let Some((eogm, typ)) = opts.scope_begin(i) {
+        let (rest, elems) = parse_nodes(&data[1..], opts, b)?;
+        Ok((
+            do_expect(data, &data[..1], rest, eogm)?,
+            b.grouped(typ, elems),
+        ))
+    } else {
+        let is_whitespace = i.is_ascii_whitespace();
+        let (cdat, rest) =
+            str_split_at_ctrl(data, opts, |x| x.is_ascii_whitespace() == is_whitespace);
+        Ok((rest, b.constant(!is_whitespace, cdat)))
+    }
+}
+
+fn parse_nodes<'a, B: Builder>(
+    mut data: &'a [u8],
+    opts: &Options,
+    b: &B,
+) -> ParseResult<'a, Vec<B::Node>> {
+    let mut ret = Vec::new();
+    while data.first().map(|&i| opts.is_scope_end(i)) == Some(false) {
+        if let Some(rest) = skip_comment(data, opts)? {
+            data = rest;
+            continue;
+        }
+        let (rest, node) = parse_node(data, opts, b)?;
+        ret.push(node);
+        data = rest;
+    }
+    Ok((data, ret))
+}
+
+// === main parser
+
+/// At top level, only parse things inside CmdEval's
+pub fn parse_toplevel<'a>(data: &'a [u8], opts: &Options) -> Result<VAN, Error<'a>> {
+    parse_toplevel_intern(data, opts, &AstBuilder, Err)
+}
+
+/// like [`parse_toplevel`], but the nodes are constructed by `b`
+pub fn parse_toplevel_with<'a, B: Builder>(
+    data: &'a [u8],
+    opts: &Options,
+    b: &B,
+) -> Result<Vec<B::Node>, Error<'a>> {
+    parse_toplevel_intern(data, opts, b, Err)
+}
+
+/// Like [`parse_toplevel`], but doesn't stop at the first error. Instead,
+/// the parser resynchronizes after each error and collects all of them.
+///
+/// # Return value
+/// * the best-effort AST, which lacks the erroneous parts
+/// * all encountered errors, in the order of their occurence
+pub fn parse_toplevel_recovering<'a>(data: &'a [u8], opts: &Options) -> (VAN, Vec<Error<'a>>) {
+    let mut errs = Vec::new();
+    let ret = parse_toplevel_intern(data, opts, &AstBuilder, |e| {
+        errs.push(e);
+        Ok(())
+    })
+    .unwrap();
+    (ret, errs)
+}
+
+/// `on_error` decides if parsing should be aborted (`Err`) or resynchronized (`Ok`)
+fn parse_toplevel_intern<'a, B: Builder>(
+    mut data: &'a [u8],
+    opts: &Options,
+    b: &B,
+    mut on_error: impl FnMut(Error<'a>) -> Result<(), Error<'a>>,
+) -> Result<Vec<B::Node>, Error<'a>> {
+    let mut ret = Vec::new();
+    while !data.is_empty() {
+        let (rest, res) = parse_toplevel_segment(data, opts, b);
+        match res {
+            Ok(node) => ret.extend(node),
+            Err(e) => on_error(e)?,
+        }
+        data = rest;
+    }
+    Ok(ret)
+}
+
+/// parses a single top-level segment, which is either a run of plain text,
+/// a comment or a node starting with the escape sequence
+///
+/// The result only depends on `data` (and not on anything before it), which is
+/// the basis of [`incremental`] re-parsing.
+///
+/// # Return value
+/// * the rest after the segment, in case of an error, the resynchronization point
+/// * the parsed node, if any
+fn parse_toplevel_segment<'a, B: Builder>(
+    data: &'a [u8],
+    opts: &Options,
+    b: &B,
+) -> (&'a [u8], Result<Option<B::Node>, Error<'a>>) {
+    let (cstp, rest) = data.split_at(data.find(&opts.escc).unwrap_or(data.len()));
+    if !cstp.is_empty() {
+        return (
+            rest,
+            Ok(Some(
+                b.constant(!cstp.iter().all(u8::is_ascii_whitespace), cstp),
+            )),
+        );
+    }
+    let res = skip_comment(data, opts).and_then(|x| match x {
+        Some(rest) => Ok((rest, None)),
+        None => parse_node(data, opts, b).map(|(rest, node)| (rest, Some(node))),
+    });
+    match res {
+        Ok((rest, node)) => (rest, Ok(node)),
+        Err(e) => (resync_point(data, &e), Err(e)),
+    }
+}
+
+/// determines where the parser should continue after the given error,
+/// which occured while parsing the top-level node starting at `data`
+fn resync_point<'a>(data: &'a [u8], e: &Error<'a>) -> &'a [u8] {
+    let origin = get_offset_of(data, e.origin);
+    let offending_end = get_offset_of(data, e.offending) + e.offending.len();
+    let pos = match e.detail {
+        // the offending part is everything after the unclosed node,
+        // thus we skip the begin of the unclosed node
+        PED::ExpectedInstead(_) | PED::UnexpectedEof => origin + 1,
+        _ => offending_end,
+    };
+    &data[std::cmp::min(std::cmp::max(pos, 1), data.len())..]
+}
+
+pub fn file2ast(filename: &std::path::Path, opts: &Options) -> Result<VAN, anyhow::Error> {
+    use anyhow::Context;
+
+    let fh = readfilez::read_from_file(std::fs::File::open(filename))
+        .with_context(|| format!("unable to read file '{}'", filename.display()))?;
+    bytes2ast(filename, fh.as_slice(), opts)
+}
+
+/// like [`file2ast`], but with already loaded file content,
+/// `filename` is only used for diagnostics
+pub fn bytes2ast(
+    filename: &std::path::Path,
+    input: &[u8],
+    opts: &Options,
+) -> Result<VAN, anyhow::Error> {
+    let (ret, errs) = parse_toplevel_recovering(input, opts);
+    if errs.is_empty() {
+        Ok(ret)
+    } else {
+        Err(report_errors(filename, input, opts, &errs))
+    }
+}
+
+/// prints a diagnostic for the given parser error to stderr
+/// and converts it into an `anyhow::Error`
+pub(crate) fn report_error(
+    filename: &std::path::Path,
+    input: &[u8],
+    opts: &Options,
+    e: Error<'_>,
+) -> anyhow::Error {
+    report_errors(filename, input, opts, &[e])
+}
+
+/// decodes `input` as UTF-8, bytes which aren't valid UTF-8 are interpreted as Latin-1
+///
+/// # Return value
+/// * the decoded string
+/// * a map from byte offsets in `input` to byte offsets in the decoded string
+fn decode_lossy(input: &[u8]) -> (String, Vec<usize>) {
+    let mut ret = String::with_capacity(input.len() + input.len() / 8);
+    let mut offsets = Vec::with_capacity(input.len() + 1);
+    // the inherent `<[u8]>::utf8_chunks` would take precedence, but needs Rust 1.79
+    for chunk in ByteSlice::utf8_chunks(input) {
+        let valid = chunk.valid();
+        offsets.extend(ret.len()..(ret.len() + valid.len()));
+        ret.push_str(valid);
+        for &i in chunk.invalid() {
+            offsets.push(ret.len());
+            ret.push(char::from(i));
+        }
+    }
+    offsets.push(ret.len());
+    (ret, offsets)
+}
+
+thread_local! {
+    /// the diagnostics printed while [`capture_diagnostics`] is running
+    static CAPTURED: std::cell::RefCell<Option<Vec<u8>>> = const { std::cell::RefCell::new(None) };
+}
+
+/// runs `f` and returns the diagnostics which would have been printed to stderr
+/// by it on this thread, e.g. to print them in a deterministic order
+pub fn capture_diagnostics<R>(f: impl FnOnce() -> R) -> (R, Vec<u8>) {
+    let outer = CAPTURED.with(|x| x.replace(Some(Vec::new())));
+    let ret = f();
+    let captured = CAPTURED.with(|x| x.replace(outer)).unwrap_or_default();
+    (ret, captured)
+}
+
+/// prints diagnostics for all given parser errors to stderr (or captures them,
+/// see [`capture_diagnostics`]) and converts them into an `anyhow::Error`
+pub(crate) fn report_errors(
+    filename: &std::path::Path,
+    input: &[u8],
+    opts: &Options,
+    errs: &[Error<'_>],
+) -> anyhow::Error {
+    use codespan_reporting::{
+        diagnostic::{Diagnostic, Label},
+        term,
+    };
+    use std::{borrow::Cow, str::FromStr};
+
+    let stderr =
+        term::termcolor::BufferWriter::stderr(term::ColorArg::from_str("auto").unwrap().into());
+    let mut writer = stderr.buffer();
+    let config = term::Config::default();
+
+    // offsets are mapped if the input isn't valid UTF-8
+    let (text, offsets) = match std::str::from_utf8(input) {
+        Ok(x) => (Cow::Borrowed(x), None),
+        Err(_) => {
+            let (text, offsets) = decode_lossy(input);
+            (Cow::Owned(text), Some(offsets))
+        }
+    };
+    let map_offset = |x: &[u8]| {
+        let pos = get_offset_of(input, x);
+        offsets.as_ref().map_or(pos, |o| o[pos])
+    };
+    let span_of = |x: &[u8]| map_offset(x)..map_offset(&input[get_offset_of(input, x) + x.len()..]);
+
+    let mut files = codespan::Files::new();
+    let fileid = files.add(filename, text);
+
+    if offsets.is_some() {
+        term::emit(
+            &mut writer,
+            &config,
+            &files,
+            &Diagnostic::warning()
+                .with_message("file contains non-UTF-8 data")
+                .with_notes(vec![
+                    "invalid bytes are shown as Latin-1 characters".to_string()
+                ]),
+        )
+        .unwrap();
+    }
+
+    for e in errs {
+        let mut primary = Label::primary(fileid, span_of(e.offending));
+        if let Some(msg) = e.primary_message() {
+            primary = primary.with_message(msg);
+        }
+        let mut labels = vec![primary];
+        if let Some(opening) = e.opening {
+            labels.push(
+                Label::secondary(fileid, span_of(opening)).with_message("unclosed delimiter"),
+            );
+        } else if e.origin.as_ptr() != e.offending.as_ptr() {
+            labels.push(
+                Label::secondary(fileid, map_offset(e.origin)..map_offset(e.offending))
+                    .with_message("error origin / parsed prefix"),
+            );
+        }
+
+        term::emit(
+            &mut writer,
+            &config,
+            &files,
+            &Diagnostic::error()
+                .with_message(e.detail.to_string())
+                .with_labels(labels)
+                .with_notes(
+                    e.help(opts)
+                        .map(|x| format!("help: {}", x))
+                        .into_iter()
+                        .collect(),
+                ),
+        )
+        .unwrap();
+    }
+
+    CAPTURED.with(|x| match &mut *x.borrow_mut() {
+        Some(captured) => captured.extend_from_slice(writer.as_slice()),
+        None => stderr.print(&writer).unwrap(),
+    });
+
+    match errs {
+        [e] => anyhow::anyhow!("{}", e.detail),
+        _ => anyhow::anyhow!("{} syntax errors", errs.len()),
+    }
+}
@@ -0,0 +1,451 @@
+#![cfg(test)]
+use super::*;
+
+fn constant(data: &str) -> ASTNode {
+    ASTNode::Constant {
+        non_space: true,
+        data: data.into(),
+    }
+}
+
+#[test]
+fn test_comments() {
+    let opts = Options::default();
+    assert_eq!(
+        parse_toplevel(b"\\%x\n\\(a \\%* ) { *%b\\% )\nc)\\%", &opts).unwrap(),
+        vec![ASTNode::CmdEval {
+            cmd: vec![constant("a")],
+            args: CmdEvalArgs(vec![constant("bc")]),
+        }]
+    );
+    assert!(parse_toplevel(b"a\\%* b", &opts).is_err());
+}
+
+#[test]
+fn test_verbatim() {
+    let opts = Options::default();
+    assert_eq!(
+        parse_toplevel(b"\\(a \\verbatim<<EOF\n$0 \\(x {\n EOF\nEOF\n b)", &opts).unwrap(),
+        vec![ASTNode::CmdEval {
+            cmd: vec![constant("a")],
+            args: CmdEvalArgs(vec![constant("$0 \\(x {\n EOF\n"), constant("b")]),
+        }]
+    );
+    // the terminator has to be on a line of its own
+    let verbatim = |input: &[u8]| parse_toplevel(input, &opts).unwrap()[0].clone();
+    assert_eq!(
+        verbatim(b"\\verbatim<<EOF\nEOFX rest\nEOF"),
+        constant("EOFX rest\n")
+    );
+    assert_eq!(
+        verbatim(b"\\verbatim<<END\nfi # ENDIF\nENDIF\r\nEND\r\nx"),
+        constant("fi # ENDIF\nENDIF\r\n")
+    );
+    assert!(parse_toplevel(b"\\verbatim<<EOF\nx\nEOF b", &opts).is_err());
+    assert!(parse_toplevel(b"\\verbatim<<EOF\nx\n EOF", &opts).is_err());
+    assert!(parse_toplevel(b"\\verbatim<< EOF\nx\nEOF", &opts).is_err());
+}
+
+#[test]
+fn test_arguments_forms() {
+    use crate::ast::{ArgumentsForm::*, Mangle as _};
+    let opts = Options::default();
+    let input = b"\\(f $@ $$* $# $$ $1)";
+    let parsed = parse_toplevel(input, &opts).unwrap();
+    let args = |indirection, form| ASTNode::Arguments { indirection, form };
+    assert_eq!(
+        parsed,
+        vec![ASTNode::CmdEval {
+            cmd: vec![constant("f")],
+            args: CmdEvalArgs(vec![
+                args(0, Remaining),
+                args(1, Joined),
+                args(0, Count),
+                ASTNode::Argument {
+                    indirection: 1,
+                    index: None
+                },
+                ASTNode::Argument {
+                    indirection: 0,
+                    index: Some(1)
+                },
+            ]),
+        }]
+    );
+    let mut out = Vec::new();
+    parsed.fmt(&mut out, &opts);
+    assert_eq!(out, input);
+
+    let toks: Vec<_> = tokens::tokenize(b"\\(f $$#)", &opts)
+        .map(|i| i.kind)
+        .collect();
+    assert!(toks.contains(&tokens::TokenKind::Arguments {
+        indirection: 1,
+        form: Count
+    }));
+}
+
+#[test]
+fn test_named_arguments() {
+    use crate::ast::Mangle as _;
+    let opts = Options::default();
+    let input = b"\\(f $href $$a_1. $1a $)";
+    let parsed = parse_toplevel(input, &opts).unwrap();
+    let named = |indirection, name: &str| ASTNode::NamedArgument {
+        indirection,
+        name: name.into(),
+    };
+    assert_eq!(
+        parsed,
+        vec![ASTNode::CmdEval {
+            cmd: vec![constant("f")],
+            args: CmdEvalArgs(vec![
+                named(0, "href"),
+                ASTNode::Grouped {
+                    typ: GroupType::Loose,
+                    elems: vec![named(1, "a_1"), constant(".")],
+                },
+                // names don't start with digits
+                ASTNode::Grouped {
+                    typ: GroupType::Loose,
+                    elems: vec![
+                        ASTNode::Argument {
+                            indirection: 0,
+                            index: Some(1)
+                        },
+                        constant("a"),
+                    ],
+                },
+                ASTNode::Argument {
+                    indirection: 0,
+                    index: None
+                },
+            ]),
+        }]
+    );
+    let mut out = Vec::new();
+    parsed.fmt(&mut out, &opts);
+    assert_eq!(out, input);
+
+    let toks: Vec<_> = tokens::tokenize(b"\\(f $$href)", &opts).collect();
+    assert!(toks.contains(&tokens::Token {
+        kind: tokens::TokenKind::NamedArgument { indirection: 1 },
+        span: 4..10,
+    }));
+}
+
+#[test]
+fn test_capture_diagnostics() {
+    let opts = Options::default();
+    let parse = |input: &[u8]| bytes2ast(std::path::Path::new("t.crulz"), input, &opts);
+    let ((outer, (inner, inner_diags)), diags) =
+        capture_diagnostics(|| (parse(b"\\(a"), capture_diagnostics(|| parse(b"abc\\(d"))));
+    assert!(outer.is_err() && inner.is_err());
+    let diags = String::from_utf8_lossy(&diags);
+    let inner_diags = String::from_utf8_lossy(&inner_diags);
+    assert!(diags.contains("t.crulz:1:4") && !diags.contains("t.crulz:1:7"));
+    assert!(inner_diags.contains("t.crulz:1:7"));
+}
+
+#[test]
+fn test_recovering() {
+    let opts = Options::default();
+    let input = b"a\\(b } c)\\() d\\(e) \\f(\\) g) \\(h";
+    let (ast, errs) = parse_toplevel_recovering(input, &opts);
+    let errs: Vec<_> = errs
+        .into_iter()
+        .map(|e| (get_offset_of(&input[..], e.offending), e.detail))
+        .collect();
+    assert_eq!(
+        format!("{:?}", errs),
+        "[(5, ExpectedInstead(41)), (9, EmptyEval), (22, DangerousEos(41)), \
+         (31, ExpectedInstead(41))]"
+    );
+    assert!(ast.contains(&ASTNode::CmdEval {
+        cmd: vec![constant("e")],
+        args: Default::default(),
+    }));
+
+    let (_, errs) = parse_toplevel_recovering(input, &opts);
+    assert_eq!(
+        errs[2].help(&opts).unwrap(),
+        "write ')' instead of '\\)', a literal '(...)' is written as an unescaped group"
+    );
+}
+
+#[test]
+fn test_diagnostics() {
+    let opts = Options::default();
+    let input = b"\\(a \\ b\\(c}";
+    let (_, errs) = parse_toplevel_recovering(input, &opts);
+    assert_eq!(errs.len(), 2);
+    assert!(matches!(errs[0].detail, PED::InvalidEval));
+    assert!(matches!(errs[1].detail, PED::ExpectedInstead(b')')));
+    assert_eq!(errs[1].offending, b"}");
+    assert_eq!(errs[1].opening, Some(&b"\\("[..]));
+    assert!(errs[1].help(&opts).unwrap().contains("stray '}'"));
+
+    let (_, errs) = parse_toplevel_recovering(b"\\() \\a(b", &opts);
+    assert!(matches!(errs[0].detail, PED::EmptyEval));
+    assert!(matches!(errs[1].detail, PED::ExpectedInstead(b')')));
+    assert_eq!(errs[1].offending, b"");
+    assert_eq!(errs[1].opening, Some(&b"("[..]));
+}
+
+#[test]
+fn test_decode_lossy() {
+    let (text, offsets) = decode_lossy(b"a\xe4\xc3\xa4\xff");
+    assert_eq!(text, "aääÿ");
+    assert_eq!(offsets, vec![0, 1, 3, 4, 5, 7]);
+}
+
+#[test]
+fn test_multibyte_escc() {
+    let opts = Options {
+        escc: "§§".as_bytes().to_vec(),
+        ..Default::default()
+    };
+    let ast = parse_toplevel("a§b§§(x y)§§§§§§{§§z".as_bytes(), &opts).unwrap();
+    assert_eq!(
+        ast,
+        vec![
+            constant("a§b"),
+            ASTNode::CmdEval {
+                cmd: vec![constant("x")],
+                args: CmdEvalArgs(vec![constant("y")]),
+            },
+            constant("§§"),
+            constant("{"),
+            ASTNode::CmdEval {
+                cmd: vec![constant("z")],
+                args: Default::default(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_tokens() {
+    use tokens::{tokenize, TokenKind::*};
+    let opts = Options::default();
+    let input: &[u8] = b"a (b) \\\\\\(add $$1 {\\x}) \\%c\n\\) \\verbatim<<E\n$\nE";
+    let toks: Vec<_> = tokenize(input, &opts)
+        .map(|i| (i.kind, input[i.span].as_bstr()))
+        .collect();
+    let arg = |indirection, index| Argument { indirection, index };
+    let expected = vec![
+        (Text, "a"),
+        (Whitespace, " "),
+        (Text, "(b)"),
+        (Whitespace, " "),
+        (Escape, "\\"),
+        (Text, "\\"),
+        (Escape, "\\"),
+        (GroupOpen(GroupType::Strict), "("),
+        (CmdName, "add"),
+        (Whitespace, " "),
+        (arg(1, Some(1)), "$$1"),
+        (Whitespace, " "),
+        (GroupOpen(GroupType::Loose), "{"),
+        (Escape, "\\"),
+        (CmdName, "x"),
+        (GroupClose(GroupType::Loose), "}"),
+        (GroupClose(GroupType::Strict), ")"),
+        (Whitespace, " "),
+        (Comment, "\\%c\n"),
+        (Error, "\\)"),
+        (Whitespace, " "),
+        (Escape, "\\"),
+        (Verbatim, "verbatim<<E\n$\nE"),
+    ];
+    assert_eq!(
+        toks,
+        expected
+            .into_iter()
+            .map(|(k, v)| (k, v.as_bytes().as_bstr()))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_tokens_cover_input() {
+    let opts = Options::default();
+    for input in [
+        &include_bytes!("../../examples/index_test.crulz")[..],
+        include_bytes!("../../examples/10.crulz"),
+        include_bytes!("../../examples/15.crulz"),
+        b"\\(a {) } \\%* x",
+        b"\\verbatim<<",
+        b"\\",
+    ] {
+        let mut pos = 0;
+        for i in tokens::tokenize(input, &opts) {
+            assert_eq!(i.span.start, pos);
+            assert!(i.span.end > pos);
+            pos = i.span.end;
+        }
+        assert_eq!(pos, input.len());
+    }
+}
+
+mod incremental {
+    use super::super::incremental::Document;
+    use super::*;
+
+    const INPUTS: &[&[u8]] = &[
+        include_bytes!("../../examples/index_test.crulz"),
+        include_bytes!("../../examples/10.crulz"),
+        include_bytes!("../../examples/14.crulz"),
+        include_bytes!("../../examples/15.crulz"),
+        b"a\\(b } c)\\() d\\(e) \\f(\\) g) \\(h",
+    ];
+
+    const SNIPPETS: &[&[u8]] = &[
+        b"",
+        b"x",
+        b" ",
+        b"\n",
+        b"\\",
+        b"\\\\",
+        b"(",
+        b")",
+        b"{",
+        b"}",
+        b"$1",
+        b"\\(a b)",
+        b"\\def(",
+        b"\\%",
+        b"\\%*",
+        b"*%",
+        b"\\verbatim<<EOF\n",
+        b"\nEOF",
+        b"\r",
+    ];
+
+    /// a simple linear congruential generator, to get reproducible edits
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self, max: usize) -> usize {
+            self.0 = self
+                .0
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            ((self.0 >> 33) as usize) % max
+        }
+    }
+
+    fn check_equivalence(doc: &Document) {
+        let full = Document::parse(doc.source().to_vec(), doc.opts().clone());
+        assert_eq!(doc.segments(), full.segments());
+
+        let (ast, errs) = parse_toplevel_recovering(doc.source(), doc.opts());
+        assert_eq!(doc.nodes().cloned().collect::<Vec<_>>(), ast);
+        assert_eq!(
+            doc.errors().map(|e| e.detail.clone()).collect::<Vec<_>>(),
+            errs.into_iter().map(|e| e.detail).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_incremental_simple() {
+        let mut doc = Document::parse(b"a\\(b c)d\\e(f)".to_vec(), Options::default());
+        doc.edit(3..4, b"x");
+        assert_eq!(doc.source(), b"a\\(x c)d\\e(f)");
+        check_equivalence(&doc);
+        doc.edit(8..8, b"\\%* ");
+        check_equivalence(&doc);
+        doc.edit(doc.source().len()..doc.source().len(), b" *%");
+        check_equivalence(&doc);
+        assert_eq!(doc.nodes().count(), 3);
+    }
+
+    #[test]
+    fn test_incremental_reuse() {
+        use super::super::incremental::PARSED_SEGMENTS;
+        let parsed = || PARSED_SEGMENTS.with(|x| x.replace(0));
+
+        let mut doc = Document::parse(
+            b"\\(a 1)\n\\(b 2)\n\\(c 3)\n\\(d 4)\n".to_vec(),
+            Options::default(),
+        );
+        let old = doc.segments().to_vec();
+        assert_eq!(parsed(), old.len());
+
+        // replaces the `2` with `22`
+        doc.edit(11..12, b"22");
+        let reparsed = parsed();
+        check_equivalence(&doc);
+        let new = doc.segments();
+        assert_eq!(new.len(), old.len());
+        // only the edited segment is parsed again
+        assert_eq!(reparsed, 1);
+        let edited = new.iter().position(|i| i.span.contains(&11)).unwrap();
+        assert_eq!(new[..edited], old[..edited]);
+        assert_ne!(new[edited], old[edited]);
+        for (x, y) in new[edited + 1..].iter().zip(&old[edited + 1..]) {
+            assert_eq!(x.span, y.span.start + 1..y.span.end + 1);
+            assert_eq!(x.result, y.result);
+        }
+    }
+
+    #[test]
+    fn test_incremental_random_edits() {
+        let mut rng = Lcg(0x5eed);
+        for &input in INPUTS {
+            let mut doc = Document::parse(input.to_vec(), Options::default());
+            for _ in 0..200 {
+                let len = doc.source().len();
+                let start = rng.next(len + 1);
+                let end = std::cmp::min(len, start + rng.next(8));
+                let snippet = SNIPPETS[rng.next(SNIPPETS.len())];
+                doc.edit(start..end, snippet);
+                check_equivalence(&doc);
+            }
+        }
+    }
+
+    #[test]
+    fn test_incremental_multibyte_escc() {
+        let opts = Options {
+            escc: b"@@".to_vec(),
+            ..Default::default()
+        };
+        let mut doc = Document::parse(b"a@ b@@(c)@".to_vec(), opts);
+        doc.edit(2..3, b"");
+        check_equivalence(&doc);
+        doc.edit(doc.source().len()..doc.source().len(), b"@d");
+        check_equivalence(&doc);
+        assert_eq!(doc.source(), b"a@b@@(c)@@d");
+        assert_eq!(doc.nodes().count(), 3);
+    }
+
+    #[test]
+    fn test_incremental_verbatim_crlf() {
+        let mut doc = Document::parse(b"\\verbatim<<T\nabc\nT\r\nx".to_vec(), Options::default());
+        assert_eq!(doc.errors().count(), 0);
+        // breaks the line ending after the end tag
+        doc.edit(19..20, b"y");
+        check_equivalence(&doc);
+        assert_eq!(doc.errors().count(), 1);
+    }
+
+    #[test]
+    fn test_incremental_report_errors() {
+        let path = std::path::Path::new("t.crulz");
+        let input = INPUTS[4];
+        let doc = Document::parse(input.to_vec(), Options::default());
+        let (res, diags) = capture_diagnostics(|| doc.report_errors(path));
+        let (expected, expected_diags) =
+            capture_diagnostics(|| bytes2ast(path, input, &Options::default()));
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            expected.unwrap_err().to_string()
+        );
+        assert_eq!(diags, expected_diags);
+
+        let doc = Document::parse(b"a\\(b)".to_vec(), Options::default());
+        let (res, diags) = capture_diagnostics(|| doc.report_errors(path));
+        assert!(res.is_ok() && diags.is_empty());
+    }
+}
@@ -0,0 +1,247 @@
+//! lexer-level view of the source code, e.g. for syntax highlighting
+//!
+//! Unlike the parser, the tokenizer never fails; code which can't be parsed
+//! is yielded as [`TokenKind::Error`] and tokenization continues after it.
+//! The spans of all tokens are contiguous and cover the whole input.
+
+use super::{
+    get_offset_of, parse_escaped_const, parse_verbatim, skip_comment, str_split_at_ctrl,
+    str_split_at_param_name, AstBuilder, Options,
+};
+use crate::ast::{ArgumentsForm, GroupType};
+use std::{collections::VecDeque, ops::Range};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    /// the escape sequence
+    Escape,
+    /// name of a command evaluation
+    CmdName,
+    /// `$n`, see [`Node::Argument`](crate::ast::Node::Argument)
+    Argument {
+        indirection: usize,
+        index: Option<usize>,
+    },
+    /// `$name`, see [`Node::NamedArgument`](crate::ast::Node::NamedArgument)
+    NamedArgument {
+        indirection: usize,
+    },
+    /// `$@`, `$*` or `$#`, see [`Node::Arguments`](crate::ast::Node::Arguments)
+    Arguments {
+        indirection: usize,
+        form: ArgumentsForm,
+    },
+    /// begin-of-scope marker of a group or command evaluation
+    GroupOpen(GroupType),
+    /// end-of-scope marker of a group or command evaluation
+    GroupClose(GroupType),
+    Whitespace,
+    Text,
+    Comment,
+    /// a verbatim block, excluding the escape sequence
+    Verbatim,
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Range<usize>,
+}
+
+/// an iterator over the tokens of the source code, see [`tokenize`]
+#[derive(Clone, Debug)]
+pub struct Tokens<'a> {
+    data: &'a [u8],
+    opts: &'a Options,
+    pos: usize,
+    /// end markers of the currently open scopes, at the top level
+    /// only escape sequences are interpreted
+    scopes: Vec<u8>,
+    /// set after the begin of a command evaluation `\(`
+    expect_cmd: bool,
+    pending: VecDeque<Token>,
+}
+
+pub fn tokenize<'a>(data: &'a [u8], opts: &'a Options) -> Tokens<'a> {
+    Tokens {
+        data,
+        opts,
+        pos: 0,
+        scopes: Vec::new(),
+        expect_cmd: false,
+        pending: VecDeque::new(),
+    }
+}
+
+impl Tokens<'_> {
+    fn push(&mut self, kind: TokenKind, len: usize) {
+        let start = self.pos;
+        self.pos += len;
+        self.pending.push_back(Token {
+            kind,
+            span: start..self.pos,
+        });
+    }
+
+    fn group_type_of_end(&self, x: u8) -> GroupType {
+        if x == self.opts.loose_markers.end {
+            GroupType::Loose
+        } else {
+            GroupType::Strict
+        }
+    }
+
+    /// tokenizes the code after an escape sequence at `self.pos`
+    fn lex_escaped(&mut self) {
+        let opts = self.opts;
+        let data = &self.data[self.pos..];
+        let after = &data[opts.escc.len()..];
+
+        match skip_comment(data, opts) {
+            Ok(Some(rest)) => return self.push(TokenKind::Comment, data.len() - rest.len()),
+            // unterminated block comment
+            Err(_) => return self.push(TokenKind::Comment, data.len()),
+            Ok(None) => {}
+        }
+
+        let i = match after.first() {
+            Some(&i) => i,
+            None => return self.push(TokenKind::Error, data.len()),
+        };
+        if i == opts.strict_markers.begin {
+            self.push(TokenKind::Escape, opts.escc.len());
+            self.push(TokenKind::GroupOpen(GroupType::Strict), 1);
+            self.scopes.push(opts.strict_markers.end);
+            self.expect_cmd = true;
+        } else if let Some((rest, _)) = parse_escaped_const(after, opts, &AstBuilder) {
+            self.push(TokenKind::Escape, opts.escc.len());
+            let len = after.len() - rest.len();
+            let kind = if after[0] == b'\n' {
+                TokenKind::Whitespace
+            } else {
+                TokenKind::Text
+            };
+            self.push(kind, len);
+        } else if let Some(res) = parse_verbatim(data, after, &AstBuilder) {
+            match res {
+                Ok((rest, _)) => {
+                    self.push(TokenKind::Escape, opts.escc.len());
+                    self.push(TokenKind::Verbatim, after.len() - rest.len());
+                }
+                Err(e) => {
+                    let end = get_offset_of(data, e.offending) + e.offending.len();
+                    self.push(TokenKind::Error, end);
+                }
+            }
+        } else if opts.is_scope_end(i) || i.is_ascii_whitespace() {
+            // dangerous escaped end-of-scope marker or invalid eval
+            self.push(TokenKind::Error, opts.escc.len() + 1);
+        } else {
+            let (cmd, rest) = str_split_at_ctrl(after, opts, |x| !x.is_ascii_whitespace());
+            self.push(TokenKind::Escape, opts.escc.len());
+            self.push(TokenKind::CmdName, cmd.len());
+            if rest.first() == Some(&opts.strict_markers.begin) {
+                self.push(TokenKind::GroupOpen(GroupType::Strict), 1);
+                self.scopes.push(opts.strict_markers.end);
+            }
+        }
+    }
+
+    /// tokenizes the code at `self.pos`, which must be inside of a scope
+    fn lex_scoped(&mut self, expect_cmd: bool) {
+        let opts = self.opts;
+        let data = &self.data[self.pos..];
+        let i = data[0];
+        if i == opts.arg_sigil {
+            let indirection = data[1..].iter().take_while(|&&x| x == i).count();
+            let form = data
+                .get(1 + indirection)
+                .copied()
+                .and_then(ArgumentsForm::from_marker);
+            if let Some(form) = form {
+                self.push(TokenKind::Arguments { indirection, form }, 2 + indirection);
+                return;
+            }
+            let (name, _) = str_split_at_param_name(&data[1 + indirection..]);
+            if !name.is_empty() {
+                self.push(
+                    TokenKind::NamedArgument { indirection },
+                    1 + indirection + name.len(),
+                );
+                return;
+            }
+            let digits = data[1 + indirection..]
+                .iter()
+                .take_while(|x| x.is_ascii_digit())
+                .count();
+            let idxs = &data[1 + indirection..1 + indirection + digits];
+            self.push(
+                TokenKind::Argument {
+                    indirection,
+                    index: atoi::atoi(idxs),
+                },
+                1 + indirection + digits,
+            );
+        } else if opts.is_scope_end(i) {
+            let typ = self.group_type_of_end(i);
+            if let Some(pos) = self.scopes.iter().rposition(|&x| x == i) {
+                // implicitly close unclosed inner scopes
+                self.scopes.truncate(pos);
+                self.push(TokenKind::GroupClose(typ), 1);
+            } else {
+                self.push(TokenKind::Error, 1);
+            }
+        } else if let Some((eogm, typ)) = opts.scope_begin(i) {
+            self.scopes.push(eogm);
+            self.push(TokenKind::GroupOpen(typ), 1);
+        } else {
+            let is_whitespace = i.is_ascii_whitespace();
+            let (x, _) =
+                str_split_at_ctrl(data, opts, |x| x.is_ascii_whitespace() == is_whitespace);
+            let kind = if is_whitespace {
+                TokenKind::Whitespace
+            } else if expect_cmd {
+                TokenKind::CmdName
+            } else {
+                TokenKind::Text
+            };
+            self.push(kind, x.len());
+        }
+    }
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        if self.pending.is_empty() {
+            let data = &self.data[self.pos..];
+            if data.is_empty() {
+                return None;
+            }
+            let expect_cmd = std::mem::replace(&mut self.expect_cmd, false);
+            if data.starts_with(&self.opts.escc) {
+                self.lex_escaped();
+            } else if !self.scopes.is_empty() {
+                self.lex_scoped(expect_cmd);
+            } else {
+                // top level text, split into white-space and other text
+                let is_whitespace = data[0].is_ascii_whitespace();
+                let len = (0..data.len())
+                    .find(|&i| {
+                        data[i].is_ascii_whitespace() != is_whitespace
+                            || data[i..].starts_with(&self.opts.escc)
+                    })
+                    .unwrap_or(data.len());
+                let kind = if is_whitespace {
+                    TokenKind::Whitespace
+                } else {
+                    TokenKind::Text
+                };
+                self.push(kind, len);
+            }
+        }
+        self.pending.pop_front()
+    }
+}
@@ -0,0 +1,221 @@
+//! per-macro profiling, see [`EvalContext::profile`](crate::interp::EvalContext::profile)
+
+use bstr::ByteSlice;
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MacroStats {
+    pub calls: usize,
+    /// time spent in the macro, including nested evaluations,
+    /// recursive calls are only counted once
+    pub total: Duration,
+    /// time spent in the macro, excluding nested evaluations
+    pub self_time: Duration,
+    /// sum of the complexity changes (see [`Mangle::get_complexity`](crate::ast::Mangle::get_complexity))
+    /// from the call to the result of each expansion, deferred calls are ignored
+    pub complexity_growth: isize,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Frame {
+    start: Instant,
+    /// time spent in nested evaluations
+    children: Duration,
+    complexity: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    pub macros: HashMap<Vec<u8>, MacroStats>,
+    /// self time per call stack (outermost macro first)
+    pub stacks: HashMap<Vec<Vec<u8>>, Duration>,
+    frames: Vec<Frame>,
+}
+
+impl Profile {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// registers the begin of an expansion, `complexity` is the complexity of the call
+    pub(crate) fn enter(&mut self, complexity: usize) {
+        self.frames.push(Frame {
+            start: Instant::now(),
+            children: Duration::default(),
+            complexity,
+        });
+    }
+
+    /// registers the end of the expansion of the innermost macro of the call stack,
+    /// `complexity` is the complexity of the result, `None` if the call was deferred
+    pub(crate) fn leave(&mut self, call_stack: &[Vec<u8>], complexity: Option<usize>) {
+        let frame = match self.frames.pop() {
+            Some(x) => x,
+            None => return,
+        };
+        let (name, outer) = match call_stack.split_last() {
+            Some(x) => x,
+            None => return,
+        };
+        let elapsed = frame.start.elapsed();
+        let self_time = elapsed.saturating_sub(frame.children);
+        if let Some(parent) = self.frames.last_mut() {
+            parent.children += elapsed;
+        }
+
+        let stats = self.macros.entry(name.clone()).or_default();
+        stats.calls += 1;
+        if !outer.contains(name) {
+            stats.total += elapsed;
+        }
+        stats.self_time += self_time;
+        if let Some(x) = complexity {
+            stats.complexity_growth += x as isize - frame.complexity as isize;
+        }
+        *self.stacks.entry(call_stack.to_vec()).or_default() += self_time;
+    }
+
+    /// adds the statistics of `other`, e.g. of another thread
+    pub fn merge(&mut self, other: Profile) {
+        for (name, x) in other.macros {
+            let stats = self.macros.entry(name).or_default();
+            stats.calls += x.calls;
+            stats.total += x.total;
+            stats.self_time += x.self_time;
+            stats.complexity_growth += x.complexity_growth;
+        }
+        for (stack, time) in other.stacks {
+            *self.stacks.entry(stack).or_default() += time;
+        }
+    }
+
+    /// returns the statistics of all macros, sorted by self time (descending)
+    pub fn sorted(&self) -> Vec<(&[u8], &MacroStats)> {
+        let mut ret: Vec<_> = self
+            .macros
+            .iter()
+            .map(|(name, stats)| (&name[..], stats))
+            .collect();
+        ret.sort_by(|a, b| b.1.self_time.cmp(&a.1.self_time).then(a.0.cmp(b.0)));
+        ret
+    }
+
+    /// writes a table of the macro statistics, sorted by self time
+    pub fn write_table<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let rows = self.sorted();
+        let width = rows
+            .iter()
+            .map(|i| i.0.to_str_lossy().chars().count())
+            .chain(std::iter::once(5))
+            .max()
+            .unwrap();
+        writeln!(
+            w,
+            "{:width$} {:>8} {:>12} {:>12} {:>12}",
+            "macro",
+            "calls",
+            "total (μs)",
+            "self (μs)",
+            "cplx growth",
+            width = width
+        )?;
+        for (name, stats) in rows {
+            writeln!(
+                w,
+                "{:width$} {:>8} {:>12} {:>12} {:>12}",
+                name.to_str_lossy(),
+                stats.calls,
+                stats.total.as_micros(),
+                stats.self_time.as_micros(),
+                stats.complexity_growth,
+                width = width
+            )?;
+        }
+        Ok(())
+    }
+
+    /// writes the self times (in nanoseconds) per call stack in the
+    /// folded-stack format, which is understood by flamegraph tools
+    pub fn write_folded<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let mut stacks: Vec<_> = self
+            .stacks
+            .iter()
+            .map(|(stack, time)| {
+                let stack: Vec<_> = stack
+                    .iter()
+                    .map(|i| i.to_str_lossy().replace(';', ":"))
+                    .collect();
+                (stack.join(";"), time.as_nanos())
+            })
+            .collect();
+        stacks.sort();
+        for (stack, time) in stacks {
+            writeln!(w, "{} {}", stack, time)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::eval_with;
+
+    fn profile(input: &str) -> Profile {
+        let (_, ctx) = eval_with(input, |ctx| ctx.profile = Some(Profile::new())).unwrap();
+        ctx.profile.unwrap()
+    }
+
+    #[test]
+    fn test_profile() {
+        let prof =
+            profile("\\def-lazy(twice 1 $0$0)\\def-lazy(f 1 \\(twice \\(add $0 1)))\\(f 1)\\(f 2)");
+        let stats = |name: &[u8]| prof.macros[name];
+        assert_eq!(stats(b"def-lazy").calls, 2);
+        assert_eq!(stats(b"f").calls, 2);
+        assert_eq!(stats(b"twice").calls, 2);
+        assert_eq!(stats(b"add").calls, 2);
+        // \(add 1 1) => 2
+        assert_eq!(stats(b"add").complexity_growth, 2 * (2 - 9));
+        // \(twice \(add 1 1)) => 22 (a loose group of two constants)
+        assert_eq!(stats(b"twice").complexity_growth, 2 * (5 - 16));
+        assert!(stats(b"f").total >= stats(b"f").self_time);
+
+        // the expansion of \(f) is evaluated after \(f) returned
+        let mut stacks: Vec<_> = prof.stacks.keys().map(|i| i.join(&b';')).collect();
+        stacks.sort();
+        assert_eq!(stacks, [&b"def-lazy"[..], b"f", b"twice", b"twice;add"]);
+    }
+
+    #[test]
+    fn test_output() {
+        let prof = profile("\\def-lazy(a 0 x)\\(a)\\(a)");
+        let mut table = Vec::new();
+        prof.write_table(&mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        let lines: Vec<_> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("macro    "));
+        assert!(lines[1..]
+            .iter()
+            .any(|i| i.starts_with("a        ") && i.split_whitespace().nth(1) == Some("2")));
+
+        let mut folded = Vec::new();
+        prof.write_folded(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        let stacks: Vec<_> = folded
+            .lines()
+            .map(|i| {
+                let (stack, time) = i.rsplit_once(' ').unwrap();
+                time.parse::<u128>().unwrap();
+                stack
+            })
+            .collect();
+        assert_eq!(stacks, ["a", "def-lazy"]);
+    }
+}
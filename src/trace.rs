@@ -0,0 +1,467 @@
+//! tracing of the evaluation, see [`EvalContext::tracer`](crate::interp::EvalContext::tracer)
+
+use crate::{
+    ast::{CmdEvalArgs, Mangle, Node as ASTNode},
+    parser::Options as ParserOptions,
+};
+use bstr::ByteSlice;
+use serde_json::{json, Value};
+use std::{
+    collections::HashSet,
+    io::{self, BufRead, Write},
+};
+
+/// a single expansion step of a macro
+#[derive(Clone, Copy, Debug)]
+pub struct TraceStep<'a> {
+    /// name of the macro, `lambda` for anonymous functions
+    pub name: &'a [u8],
+    /// the evaluated command name, a constant or a lambda
+    pub callee: &'a ASTNode,
+    /// the arguments, as far as they were evaluated by the macro
+    pub args: &'a CmdEvalArgs,
+    /// the result of the expansion, `None` if the macro couldn't be evaluated (yet)
+    pub result: Option<&'a ASTNode>,
+    /// count of the enclosing macro evaluations
+    pub depth: usize,
+    /// byte offset of the top-level node of the input from which this step originates,
+    /// only available when evaluating via [`eval_spanned`](crate::interp::eval_spanned)
+    pub location: Option<usize>,
+}
+
+impl TraceStep<'_> {
+    /// formats the call, e.g. `\(name arg1 arg2)`
+    pub fn fmt_call(&self, f: &mut Vec<u8>, opts: &ParserOptions) {
+        ASTNode::CmdEval {
+            cmd: vec![self.callee.clone()],
+            args: self.args.clone(),
+        }
+        .fmt(f, opts);
+    }
+}
+
+/// receives the expansion steps of the evaluation
+pub trait Tracer: Send {
+    /// called before a macro is evaluated, thus `step.result` is always `None`
+    /// and the arguments aren't evaluated yet
+    ///
+    /// # Return value
+    /// * `false` aborts the evaluation
+    fn enter(&mut self, _step: &TraceStep<'_>) -> bool {
+        true
+    }
+
+    /// called after each expansion step, inner steps are reported before outer steps
+    fn step(&mut self, step: &TraceStep<'_>);
+
+    /// called after the evaluation, flushes the output and
+    /// returns the first error which occurred while writing it
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// maps byte offsets into the input to line and column numbers
+#[derive(Clone, Debug)]
+pub struct SourceInfo {
+    name: String,
+    line_starts: Vec<usize>,
+}
+
+impl SourceInfo {
+    pub fn new(name: impl Into<String>, source: &[u8]) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.find_iter(b"\n").map(|i| i + 1))
+            .collect();
+        Self {
+            name: name.into(),
+            line_starts,
+        }
+    }
+
+    /// returns the (1-based) line and column (in bytes) of the offset
+    pub fn position(&self, offset: usize) -> (usize, usize) {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(x) => x,
+            Err(x) => x - 1,
+        };
+        (line + 1, offset - self.line_starts[line] + 1)
+    }
+
+    fn describe(&self, offset: usize) -> String {
+        let (line, column) = self.position(offset);
+        format!("{}:{}:{}", self.name, line, column)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// one line per step, indented by the depth
+    Text,
+    /// one JSON object per line
+    Json,
+}
+
+/// writes the trace in the given format
+pub struct TraceWriter<W> {
+    out: W,
+    format: TraceFormat,
+    opts: ParserOptions,
+    source: Option<SourceInfo>,
+    /// the first write error, nothing is written after it, see [`Tracer::finish`]
+    error: Option<io::Error>,
+}
+
+impl<W: Write + Send> TraceWriter<W> {
+    pub fn new(out: W, format: TraceFormat, opts: ParserOptions) -> Self {
+        Self {
+            out,
+            format,
+            opts,
+            source: None,
+            error: None,
+        }
+    }
+
+    /// enables the output of source locations
+    pub fn with_source(mut self, source: SourceInfo) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    fn fmt_text(&self, step: &TraceStep<'_>) -> Vec<u8> {
+        let mut ret = b"  ".repeat(step.depth);
+        step.fmt_call(&mut ret, &self.opts);
+        ret.extend_from_slice(b" => ");
+        match step.result {
+            Some(x) => x.fmt(&mut ret, &self.opts),
+            None => ret.extend_from_slice(b"(deferred)"),
+        }
+        if let (Some(src), Some(loc)) = (&self.source, step.location) {
+            ret.extend_from_slice(b"  @ ");
+            ret.extend_from_slice(src.describe(loc).as_bytes());
+        }
+        ret
+    }
+
+    fn fmt_json(&self, step: &TraceStep<'_>) -> Vec<u8> {
+        let fmt = |x: &ASTNode| {
+            let mut ret = Vec::new();
+            x.fmt(&mut ret, &self.opts);
+            ret.to_str_lossy().into_owned()
+        };
+        let location = match (&self.source, step.location) {
+            (_, None) => Value::Null,
+            (None, Some(offset)) => json!({ "offset": offset }),
+            (Some(src), Some(offset)) => {
+                let (line, column) = src.position(offset);
+                json!({ "file": src.name, "line": line, "column": column, "offset": offset })
+            }
+        };
+        let value = json!({
+            "name": step.name.to_str_lossy(),
+            "args": step.args.iter().map(fmt).collect::<Vec<_>>(),
+            "result": step.result.map(fmt),
+            "depth": step.depth,
+            "location": location,
+        });
+        value.to_string().into_bytes()
+    }
+}
+
+impl<W: Write + Send> Tracer for TraceWriter<W> {
+    fn step(&mut self, step: &TraceStep<'_>) {
+        if self.error.is_some() {
+            return;
+        }
+        let mut line = match self.format {
+            TraceFormat::Text => self.fmt_text(step),
+            TraceFormat::Json => self.fmt_json(step),
+        };
+        line.push(b'\n');
+        if let Err(e) = self.out.write_all(&line) {
+            self.error = Some(e);
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+    }
+}
+
+/// an interactive stepper, which stops before the evaluation of the given macros
+/// and asks how to continue
+pub struct Stepper<R, W> {
+    breakpoints: HashSet<Vec<u8>>,
+    input: R,
+    out: W,
+    opts: ParserOptions,
+    source: Option<SourceInfo>,
+    /// stop before the next evaluation, regardless of the breakpoints
+    stepping: bool,
+    /// receives all steps, e.g. a [`TraceWriter`]
+    inner: Option<Box<dyn Tracer>>,
+}
+
+const STEPPER_HELP: &str = "commands: (s)tep, (c)ontinue, (q)uit";
+
+impl<R: BufRead + Send, W: Write + Send> Stepper<R, W> {
+    pub fn new(
+        breakpoints: impl IntoIterator<Item = Vec<u8>>,
+        input: R,
+        out: W,
+        opts: ParserOptions,
+    ) -> Self {
+        Self {
+            breakpoints: breakpoints.into_iter().collect(),
+            input,
+            out,
+            opts,
+            source: None,
+            stepping: false,
+            inner: None,
+        }
+    }
+
+    /// enables the output of source locations
+    pub fn with_source(mut self, source: SourceInfo) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// forwards all steps to the given tracer
+    pub fn with_inner(mut self, inner: Box<dyn Tracer>) -> Self {
+        self.inner = Some(inner);
+        self
+    }
+
+    /// shows the step and reads the next command
+    ///
+    /// # Return value
+    /// * `false` if the evaluation should be aborted
+    fn prompt(&mut self, step: &TraceStep<'_>) -> bool {
+        let mut msg = b"break: ".to_vec();
+        step.fmt_call(&mut msg, &self.opts);
+        msg.extend_from_slice(format!(" (depth {})", step.depth).as_bytes());
+        if let (Some(src), Some(loc)) = (&self.source, step.location) {
+            msg.extend_from_slice(b" @ ");
+            msg.extend_from_slice(src.describe(loc).as_bytes());
+        }
+        msg.push(b'\n');
+        self.out.write_all(&msg).expect("unable to write prompt");
+
+        loop {
+            self.out
+                .write_all(b"(crulz) ")
+                .and_then(|()| self.out.flush())
+                .expect("unable to write prompt");
+            let mut line = String::new();
+            if self
+                .input
+                .read_line(&mut line)
+                .expect("unable to read command")
+                == 0
+            {
+                // end of input, run to completion
+                self.breakpoints.clear();
+                self.stepping = false;
+                return true;
+            }
+            match line.trim() {
+                "" | "s" | "step" => {
+                    self.stepping = true;
+                    return true;
+                }
+                "c" | "continue" => {
+                    self.stepping = false;
+                    return true;
+                }
+                "q" | "quit" => return false,
+                _ => writeln!(self.out, "{}", STEPPER_HELP).expect("unable to write prompt"),
+            }
+        }
+    }
+}
+
+impl<R: BufRead + Send, W: Write + Send> Tracer for Stepper<R, W> {
+    fn enter(&mut self, step: &TraceStep<'_>) -> bool {
+        if let Some(inner) = &mut self.inner {
+            if !inner.enter(step) {
+                return false;
+            }
+        }
+        if self.stepping || self.breakpoints.contains(step.name) {
+            self.prompt(step)
+        } else {
+            true
+        }
+    }
+
+    fn step(&mut self, step: &TraceStep<'_>) {
+        if let Some(inner) = &mut self.inner {
+            inner.step(step);
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            Some(inner) => inner.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::{eval_spanned, EvalContext, EvalError};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    /// a writer whose content stays accessible after moving it into the tracer
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn content(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn run(input: &str, tracer: impl Tracer + 'static) -> Result<Vec<u8>, EvalError> {
+        let opts = ParserOptions::default();
+        let doc = crate::parser::incremental::Document::parse(input.into(), opts.clone());
+        let nodes = doc
+            .segments()
+            .iter()
+            .filter_map(|i| Some((i.span.start, i.result.clone().ok()??)))
+            .collect();
+        let mut ctx = EvalContext::new(opts, HashMap::new());
+        ctx.tracer = Some(Box::new(tracer));
+        let data = eval_spanned(nodes, &mut ctx, None)?;
+        let mut ret = Vec::new();
+        data.fmt(&mut ret, &ctx.opts);
+        Ok(ret)
+    }
+
+    const INPUT: &str = "\\def-lazy(a 1 <$0>)\n\\(a \\(add 1 2))";
+
+    #[test]
+    fn test_source_info() {
+        let src = SourceInfo::new("x", b"ab\n\ncd");
+        assert_eq!(src.position(0), (1, 1));
+        assert_eq!(src.position(2), (1, 3));
+        assert_eq!(src.position(3), (2, 1));
+        assert_eq!(src.position(5), (3, 2));
+        assert_eq!(src.describe(4), "x:3:1");
+    }
+
+    #[test]
+    fn test_trace_text() {
+        let out = Shared::default();
+        let tracer = TraceWriter::new(out.clone(), TraceFormat::Text, Default::default())
+            .with_source(SourceInfo::new("in", INPUT.as_bytes()));
+        assert_eq!(run(INPUT, tracer).unwrap(), b"\n<3>");
+        assert_eq!(
+            out.content(),
+            "\\(def-lazy a 1 <$0>) =>   @ in:1:1\n  \
+             \\(add 1 2) => 3  @ in:2:1\n\
+             \\(a 3) => <3>  @ in:2:1\n"
+        );
+    }
+
+    #[test]
+    fn test_trace_json() {
+        let out = Shared::default();
+        let tracer = TraceWriter::new(out.clone(), TraceFormat::Json, Default::default());
+        run("\\(x)\\(add 1 2)", tracer).unwrap();
+        let lines: Vec<Value> = out
+            .content()
+            .lines()
+            .map(|i| serde_json::from_str(i).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            json!({ "name": "x", "args": [], "result": null, "depth": 0, "location": { "offset": 0 } })
+        );
+        assert_eq!(lines[1]["name"], "add");
+        assert_eq!(lines[1]["args"], json!(["1", "2"]));
+        assert_eq!(lines[1]["result"], "3");
+    }
+
+    #[test]
+    fn test_trace_write_error() {
+        struct Failing(usize);
+
+        impl Write for Failing {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                self.0 += 1;
+                Err(io::Error::other("disk full"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut tracer = TraceWriter::new(Failing(0), TraceFormat::Text, Default::default());
+        let step = TraceStep {
+            name: b"x",
+            callee: &ASTNode::NullNode,
+            args: &CmdEvalArgs::default(),
+            result: None,
+            depth: 0,
+            location: None,
+        };
+        tracer.step(&step);
+        tracer.step(&step);
+        // nothing is written after the first error
+        assert_eq!(tracer.out.0, 1);
+        assert_eq!(tracer.finish().unwrap_err().to_string(), "disk full");
+        assert!(tracer.finish().is_ok());
+    }
+
+    #[test]
+    fn test_stepper() {
+        let out = Shared::default();
+        let stepper = Stepper::new(
+            vec![b"a".to_vec()],
+            &b"x\ns\nc\n"[..],
+            out.clone(),
+            Default::default(),
+        );
+        assert_eq!(run(INPUT, stepper).unwrap(), b"\n<3>");
+        assert_eq!(
+            out.content(),
+            format!(
+                "break: \\(a \\(add 1 2)) (depth 0)\n(crulz) {}\n(crulz) \
+                 break: \\(add 1 2) (depth 1)\n(crulz) ",
+                STEPPER_HELP
+            )
+        );
+
+        let stepper = Stepper::new(
+            vec![b"add".to_vec()],
+            &b"q\n"[..],
+            Shared::default(),
+            Default::default(),
+        );
+        assert_eq!(run(INPUT, stepper), Err(EvalError::Aborted));
+    }
+}